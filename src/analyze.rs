@@ -0,0 +1,398 @@
+//! Steganalysis of the least significant bits of an image.
+//!
+//! Every test gives a score between 0 (no sign of hidden data) and 1:
+//!
+//! | Test                 | Score                                              |
+//! |----------------------|----------------------------------------------------|
+//! | chi-square attack    | p-value that pairs of values `2k`, `2k + 1` are equalised |
+//! | RS analysis          | estimated share of samples carrying payload        |
+//! | sample pair analysis | estimated share of samples carrying payload        |
+//! | transparent pixels   | entropy of the colour of fully transparent pixels  |
+//!
+//! The statistical tests look at the colour samples of every pixel in their
+//! own depth, 16-bit samples as 16-bit values and float samples as their
+//! bits, so the low byte the payload sits in is not rounded away. They
+//! detect plain LSB replacement; LSB matching and Hamming encoding are meant
+//! to stay below them.
+
+use crate::Canvas;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Result of one test of [`analyze`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub test: &'static str,
+    /// Suspicion between 0 and 1.
+    pub score: f64,
+    /// What the score measures.
+    pub detail: String,
+}
+
+/// Results of all tests of [`analyze`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Analysis {
+    pub findings: Vec<Finding>,
+}
+
+/// Overall suspicion of an [`Analysis`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Clean,
+    Suspicious,
+    Likely,
+}
+
+impl Analysis {
+    /// Highest score of any test.
+    pub fn score(&self) -> f64 {
+        self.findings
+            .iter()
+            .map(|finding| finding.score)
+            .fold(0.0, f64::max)
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        match self.score() {
+            score if score >= 0.5 => Verdict::Likely,
+            score if score >= 0.1 => Verdict::Suspicious,
+            _ => Verdict::Clean,
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Clean => "no sign of hidden data",
+            Verdict::Suspicious => "suspicious",
+            Verdict::Likely => "likely carries hidden data",
+        })
+    }
+}
+
+/// Runs every test on `canvas`, on the colours of indexed images.
+pub fn analyze(canvas: &Canvas) -> Analysis {
+    let true_colour;
+    let canvas = match canvas.palette() {
+        Some(_) => {
+            true_colour = Canvas::from(canvas.to_rgba8());
+            &true_colour
+        }
+        None => canvas,
+    };
+    let (depth, colour) = (canvas.depth(), canvas.colour_channels());
+    let samples = canvas
+        .samples()
+        .chunks_exact(canvas.channels() * depth)
+        .flat_map(|pixel| pixel[..colour * depth].chunks_exact(depth).map(value))
+        .collect::<Vec<_>>();
+    let row = canvas.width() as usize * colour;
+    let channels = (0..colour)
+        .map(|channel| {
+            samples
+                .chunks_exact(row.max(1))
+                .map(|row| row.iter().skip(channel).step_by(colour).copied().collect())
+                .collect()
+        })
+        .collect::<Vec<Vec<Vec<i64>>>>();
+
+    Analysis {
+        findings: vec![
+            chi_square(&samples),
+            rs_analysis(&channels),
+            sample_pairs(&channels),
+            transparent(canvas),
+        ],
+    }
+}
+
+/// A little-endian sample as an integer, float samples by their bits.
+fn value(sample: &[u8]) -> i64 {
+    match *sample {
+        [byte] => byte as i64,
+        [low, high] => u16::from_le_bytes([low, high]) as i64,
+        _ => u32::from_le_bytes(sample.try_into().unwrap()) as i64,
+    }
+}
+
+/// Westfeld and Pfitzmann's chi-square attack, evaluated on growing parts
+/// of the image since payloads usually fill it from the start.
+fn chi_square(samples: &[i64]) -> Finding {
+    // Counts of the even and odd value of every pair `2k`, `2k + 1`.
+    let mut histogram = HashMap::<i64, [u64; 2]>::new();
+    let (mut score, mut part) = (0.0, 8);
+    for eighth in 1..=8 {
+        let range = samples.len() * (eighth - 1) / 8..samples.len() * eighth / 8;
+        for &sample in &samples[range] {
+            histogram.entry(sample >> 1).or_default()[(sample & 1) as usize] += 1;
+        }
+
+        let mut statistic = 0.0;
+        let mut pairs = 0;
+        for pair in histogram.values() {
+            let expected = (pair[0] + pair[1]) as f64 / 2.0;
+            if expected >= 5.0 {
+                statistic += (pair[0] as f64 - expected).powi(2) / expected;
+                pairs += 1;
+            }
+        }
+
+        let p = match pairs {
+            0 | 1 => 0.0,
+            _ => 1.0 - gamma_p((pairs - 1) as f64 / 2.0, statistic / 2.0),
+        };
+        if p > score {
+            (score, part) = (p, eighth);
+        }
+    }
+
+    Finding {
+        test: "chi-square attack",
+        score,
+        detail: format!("p-value of equalised value pairs in the first {part}/8"),
+    }
+}
+
+/// Fridrich's RS analysis with the mask `0 1 1 0` on groups of four
+/// neighbouring samples.
+fn rs_analysis(channels: &[Vec<Vec<i64>>]) -> Finding {
+    let flip = |x: i64| x ^ 1;
+    let shift = |x: i64| ((x + 1) ^ 1) - 1;
+
+    // Regular minus singular groups for the positive and negative mask.
+    let count = |invert: bool| {
+        let (mut positive, mut negative, mut groups) = (0i64, 0i64, 0i64);
+        for row in channels.iter().flatten() {
+            for group in row.chunks_exact(4) {
+                let group = group
+                    .iter()
+                    .map(|&x| if invert { flip(x) } else { x })
+                    .collect::<Vec<_>>();
+                let smoothness = variation(&group);
+                let masked = |f: &dyn Fn(i64) -> i64| {
+                    variation(&[group[0], f(group[1]), f(group[2]), group[3]])
+                };
+                positive += (masked(&flip) - smoothness).signum();
+                negative += (masked(&shift) - smoothness).signum();
+                groups += 1;
+            }
+        }
+        let groups = groups.max(1) as f64;
+        (positive as f64 / groups, negative as f64 / groups)
+    };
+
+    let (d0, dn0) = count(false);
+    let (d1, dn1) = count(true);
+    let a = 2.0 * (d1 + d0);
+    let b = dn0 - dn1 - d1 - 3.0 * d0;
+    let c = d0 - dn0;
+
+    let rate = smaller_root(a, b, c).map(|z| z / (z - 0.5));
+    estimate("RS analysis", rate)
+}
+
+/// Dumitrescu, Wu and Wang's sample pair analysis on horizontally
+/// neighbouring samples.
+fn sample_pairs(channels: &[Vec<Vec<i64>>]) -> Finding {
+    let (mut x, mut y, mut z, mut w, mut pairs) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for row in channels.iter().flatten() {
+        for pair in row.windows(2) {
+            let (u, v) = (pair[0], pair[1]);
+            let even = v % 2 == 0;
+            if (even && u < v) || (!even && u > v) {
+                x += 1.0;
+            }
+            if (even && u > v) || (!even && u < v) {
+                y += 1.0;
+            }
+            if u == v {
+                z += 1.0;
+            } else if u >> 1 == v >> 1 {
+                w += 1.0;
+            }
+            pairs += 1.0;
+        }
+    }
+
+    let rate = smaller_root((w + z) / 2.0, 2.0 * x - pairs, y - x);
+    estimate("sample pair analysis", rate)
+}
+
+fn estimate(test: &'static str, rate: Option<f64>) -> Finding {
+    match rate {
+        Some(rate) if rate.is_finite() => Finding {
+            test,
+            score: rate.clamp(0.0, 1.0),
+            detail: format!("estimated share of samples carrying payload: {rate:.3}"),
+        },
+        _ => Finding {
+            test,
+            score: 0.0,
+            detail: "no estimate for this image".into(),
+        },
+    }
+}
+
+/// Fully transparent pixels look the same whatever their colour, so
+/// encoders and editors normally leave them black or fill them with a few
+/// colours. Colour as random as a payload is suspicious.
+fn transparent(canvas: &Canvas) -> Finding {
+    let mut histogram = [0u64; 256];
+    let (mut transparent, mut coloured, mut bytes) = (0u64, 0u64, 0u64);
+    let colour = canvas.colour_channels() * canvas.depth();
+    let pixels = canvas
+        .samples()
+        .chunks_exact(canvas.channels() * canvas.depth());
+    for (_, pixel) in pixels
+        .enumerate()
+        .filter(|&(at, _)| canvas.is_transparent(at))
+    {
+        transparent += 1;
+        if pixel[..colour].iter().any(|&byte| byte != 0) {
+            coloured += 1;
+            for &byte in &pixel[..colour] {
+                histogram[byte as usize] += 1;
+                bytes += 1;
+            }
+        }
+    }
+
+    let samples = bytes as f64;
+    let entropy = histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count as f64 / samples)
+        .map(|p| -p * p.log2())
+        .sum::<f64>();
+
+    Finding {
+        test: "transparent pixels",
+        score: match coloured {
+            0 => 0.0,
+            _ => (entropy / samples.log2().clamp(1.0, 8.0)).min(1.0),
+        },
+        detail: match coloured {
+            0 => format!("none of {transparent} transparent pixels has colour"),
+            _ => format!(
+                "{coloured} of {transparent} transparent pixels have colour \
+                 with {entropy:.2} bits of entropy per byte"
+            ),
+        },
+    }
+}
+
+fn variation(group: &[i64]) -> i64 {
+    group.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum()
+}
+
+/// Root of `a x² + b x + c` closest to zero.
+fn smaller_root(a: f64, b: f64, c: f64) -> Option<f64> {
+    if a.abs() < f64::EPSILON {
+        return (b != 0.0).then(|| -c / b);
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return (b != 0.0).then(|| -c / b);
+    }
+    let roots = [
+        (-b + discriminant.sqrt()) / (2.0 * a),
+        (-b - discriminant.sqrt()) / (2.0 * a),
+    ];
+    Some(if roots[0].abs() <= roots[1].abs() {
+        roots[0]
+    } else {
+        roots[1]
+    })
+}
+
+/// Regularised lower incomplete gamma function `P(a, x)`.
+fn gamma_p(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let front = (a * x.ln() - x - ln_gamma(a)).exp();
+
+    if x < a + 1.0 {
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        while term.abs() > sum.abs() * 1e-15 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+        }
+        return sum * front;
+    }
+
+    // Continued fraction for the upper function, evaluated by Lentz's method.
+    let tiny = 1e-300;
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / tiny;
+    let mut d = 1.0 / b;
+    let mut h = d;
+    for i in 1..1000 {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        d = if d.abs() < tiny { 1.0 / tiny } else { 1.0 / d };
+        c = b + an / c;
+        if c.abs() < tiny {
+            c = tiny;
+        }
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+    1.0 - front * h
+}
+
+/// Lanczos approximation of `ln Γ(x)`.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = COEFFICIENTS
+        .iter()
+        .enumerate()
+        .fold(1.000000000190015, |sum, (i, c)| {
+            sum + c / (x + 1.0 + i as f64)
+        });
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    /// A 16-bit image widened from 8-bit shading and grain, with random
+    /// least significant bits when `payload`.
+    fn widened(payload: bool) -> Canvas {
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        Canvas::from(ImageBuffer::from_fn(300, 200, |x, y| {
+            let shade = 120.0 + 80.0 * (x as f32 / 37.0).sin() * (y as f32 / 29.0).cos();
+            Rgb([0.7, 1.0, 1.3].map(|tint| {
+                let sample = (shade * tint + rng.gen_range(-3.0..3.0)).clamp(0.0, 255.0) as u16;
+                match payload {
+                    true => (sample * 257) & !1 | rng.gen_range(0..2),
+                    false => sample * 257,
+                }
+            }))
+        }))
+    }
+
+    #[test]
+    fn payload_in_the_low_byte_of_16_bit_samples_is_found() {
+        assert_eq!(analyze(&widened(false)).verdict(), Verdict::Clean);
+        assert_eq!(analyze(&widened(true)).verdict(), Verdict::Likely);
+    }
+}
@@ -0,0 +1,73 @@
+//! Container holding several files in one payload.
+//!
+//! The container starts with the little-endian `u32` number of entries.
+//! Each entry is a [`Metadata`] record, the little-endian `u64` length of
+//! the data and the data itself.
+
+use crate::{Metadata, Payload, Result};
+
+const TRUNCATED: &str = "archive is truncated";
+
+/// Files hidden together in one image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Archive {
+    pub entries: Vec<Payload>,
+}
+
+impl Archive {
+    /// Entry stored under `name`.
+    pub fn get(&self, name: &str) -> Option<&Payload> {
+        self.entries
+            .iter()
+            .find(|entry| entry.metadata.name.as_deref() == Some(name))
+    }
+
+    /// Adds `entry`, replacing an entry stored under the same name.
+    pub fn insert(&mut self, entry: Payload) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|old| old.metadata.name.is_some() && old.metadata.name == entry.metadata.name)
+        {
+            Some(old) => *old = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let Ok(count) = u32::try_from(self.entries.len()) else {
+            return Err("too many files in the archive".into());
+        };
+
+        let mut bytes = count.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            bytes.append(&mut entry.metadata.to_bytes()?);
+            bytes.extend_from_slice(&(entry.data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&entry.data);
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Archive> {
+        let count = bytes.get(..4).ok_or(TRUNCATED)?;
+        let count = u32::from_le_bytes(count.try_into().unwrap());
+
+        let mut archive = Archive::default();
+        let mut rest = &bytes[4..];
+        for _ in 0..count {
+            let (metadata, len) = Metadata::from_bytes(rest)?;
+            rest = &rest[len..];
+
+            let size = rest.get(..8).ok_or(TRUNCATED)?;
+            let size = u64::from_le_bytes(size.try_into().unwrap());
+            let size = usize::try_from(size).map_err(|_| TRUNCATED)?;
+            let data = rest.get(8..).and_then(|rest| rest.get(..size));
+            let data = data.ok_or(TRUNCATED)?.to_vec();
+            rest = &rest[8 + size..];
+
+            archive.entries.push(Payload { metadata, data });
+        }
+
+        Ok(archive)
+    }
+}
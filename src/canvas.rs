@@ -0,0 +1,723 @@
+//! Image samples kept in the colour type and depth of their source.
+//!
+//! 16-bit and float samples are stored little endian, so the first byte of
+//! every sample is its least significant one whatever the platform. Indexed
+//! PNG images keep their [`Palette`] and hold one palette index per pixel.
+//! PNG images also keep their ancillary chunks, see [`crate::chunk`], and
+//! baseline JPEG images their DCT coefficients, see [`crate::jpeg`].
+
+use crate::chunk::{self, Chunk};
+use crate::jpeg::Jpeg;
+use crate::Result;
+use image::{ColorType, DynamicImage, ImageBuffer, ImageFormat, Pixel, RgbImage, RgbaImage};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Pixels of an image in its native [`ColorType`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    color: ColorType,
+    data: Vec<u8>,
+    palette: Option<Palette>,
+    chunks: Vec<Chunk>,
+    jpeg: Option<Jpeg>,
+}
+
+/// Colour table of an indexed image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// Red, green and blue of every entry.
+    pub colours: Vec<[u8; 3]>,
+    /// Alpha of the first entries, the others are opaque.
+    pub alpha: Vec<u8>,
+}
+
+impl Palette {
+    pub fn len(&self) -> usize {
+        self.colours.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colours.is_empty()
+    }
+
+    /// Colour and alpha of entry `index`, black for missing entries.
+    pub fn entry(&self, index: usize) -> [u8; 4] {
+        let [r, g, b] = self.colours.get(index).copied().unwrap_or_default();
+        [r, g, b, self.alpha.get(index).copied().unwrap_or(255)]
+    }
+
+    /// Whether entry `index` has a twin of the same colour at `index ^ 1`.
+    pub fn is_paired(&self, index: usize) -> bool {
+        index < self.len() && (index ^ 1) < self.len() && self.entry(index) == self.entry(index ^ 1)
+    }
+
+    fn from_entries(entries: &[[u8; 4]]) -> Palette {
+        let mut alpha = entries.iter().map(|entry| entry[3]).collect::<Vec<_>>();
+        while alpha.last() == Some(&255) {
+            alpha.pop();
+        }
+        Palette {
+            colours: entries.iter().map(|&[r, g, b, _]| [r, g, b]).collect(),
+            alpha,
+        }
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Canvas {
+        RgbaImage::default().into()
+    }
+}
+
+impl Canvas {
+    /// Indexed image holding one index into `palette` per pixel.
+    pub fn indexed(width: u32, height: u32, indices: Vec<u8>, palette: Palette) -> Canvas {
+        assert_eq!(indices.len(), width as usize * height as usize);
+        Canvas {
+            width,
+            height,
+            color: ColorType::L8,
+            data: indices,
+            palette: Some(palette),
+            chunks: Vec::new(),
+            jpeg: None,
+        }
+    }
+
+    /// Opens the image at `path`, keeping the palette and the ancillary
+    /// chunks of PNGs and the coefficients of JPEGs.
+    pub fn open(path: &Path) -> Result<Canvas> {
+        match ImageFormat::from_path(path).ok() {
+            Some(ImageFormat::Png) => {}
+            Some(ImageFormat::Jpeg) => {
+                let bytes = fs::read(path)?;
+                let mut canvas: Canvas =
+                    image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?.into();
+                // Progressive images are only read as pixels.
+                canvas.jpeg = Jpeg::decode(&bytes).ok();
+                return Ok(canvas);
+            }
+            _ => return Ok(image::open(path)?.into()),
+        }
+        let bytes = fs::read(path)?;
+        let mut canvas = match Canvas::decode_indexed(&bytes)? {
+            Some(canvas) => canvas,
+            None => image::load_from_memory_with_format(&bytes, ImageFormat::Png)?.into(),
+        };
+        canvas.chunks = chunk::ancillary(&bytes)?;
+        Ok(canvas)
+    }
+
+    fn decode_indexed(bytes: &[u8]) -> Result<Option<Canvas>> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info()?;
+
+        let info = reader.info();
+        if info.color_type != png::ColorType::Indexed {
+            return Ok(None);
+        }
+        let palette = Palette {
+            colours: info
+                .palette
+                .as_deref()
+                .unwrap_or_default()
+                .chunks_exact(3)
+                .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                .collect(),
+            alpha: info.trns.as_deref().unwrap_or_default().to_vec(),
+        };
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buffer)?;
+        let depth = frame.bit_depth as usize;
+        let indices = buffer
+            .chunks(frame.line_size)
+            .take(frame.height as usize)
+            .flat_map(|line| {
+                (0..frame.width as usize).map(move |x| {
+                    let bit = x * depth;
+                    line[bit / 8] >> (8 - depth - bit % 8) & ((1u16 << depth) - 1) as u8
+                })
+            })
+            .collect();
+
+        Ok(Some(Canvas::indexed(
+            frame.width,
+            frame.height,
+            indices,
+            palette,
+        )))
+    }
+
+    /// Writes the image to `path` in `format`, as an indexed PNG when it
+    /// has a palette. PNGs get the ancillary chunks of the source back, and
+    /// JPEGs are written from the coefficients when there are any.
+    pub fn save(&self, path: &Path, format: ImageFormat) -> Result<()> {
+        let mut bytes = match (&self.jpeg, format) {
+            (Some(jpeg), ImageFormat::Jpeg) => jpeg.encode()?,
+            _ => self.encode(format)?,
+        };
+        if format == ImageFormat::Png && !self.chunks.is_empty() {
+            bytes = chunk::insert(&bytes, &self.chunks)?;
+        }
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    fn encode(&self, format: ImageFormat) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let Some(palette) = self.palette.as_ref().filter(|_| format == ImageFormat::Png) else {
+            self.clone()
+                .into_image()
+                .write_to(&mut Cursor::new(&mut bytes), format)?;
+            return Ok(bytes);
+        };
+
+        let depth = [1, 2, 4, 8]
+            .into_iter()
+            .find(|&depth| palette.len() <= 1 << depth)
+            .unwrap();
+        let mut encoder = png::Encoder::new(&mut bytes, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::from_u8(depth as u8).unwrap());
+        encoder.set_palette(palette.colours.concat());
+        if !palette.alpha.is_empty() {
+            encoder.set_trns(palette.alpha.clone());
+        }
+
+        let line = (self.width as usize * depth).div_ceil(8);
+        let mut packed = vec![0; line * self.height as usize];
+        for (at, &index) in self.data.iter().enumerate() {
+            let (y, x) = (at / self.width as usize, at % self.width as usize);
+            let bit = x * depth;
+            packed[y * line + bit / 8] |= index << (8 - depth - bit % 8);
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&packed)?;
+        writer.finish()?;
+        Ok(bytes)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Colour type of the samples, [`ColorType::L8`] for the indices of an
+    /// indexed image.
+    pub fn color(&self) -> ColorType {
+        self.color
+    }
+
+    pub fn palette(&self) -> Option<&Palette> {
+        self.palette.as_ref()
+    }
+
+    /// Ancillary chunks of the source PNG written back on [`Canvas::save`].
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    pub fn chunks_mut(&mut self) -> &mut Vec<Chunk> {
+        &mut self.chunks
+    }
+
+    /// Drops the ancillary chunks of the source, so the output only
+    /// carries what the encoder writes.
+    pub fn strip_metadata(&mut self) {
+        self.chunks.clear();
+    }
+
+    /// DCT coefficients of the source JPEG, unless the samples changed
+    /// since.
+    pub fn jpeg(&self) -> Option<&Jpeg> {
+        self.jpeg.as_ref()
+    }
+
+    /// Compresses the pixels into JPEG coefficients unless the image has
+    /// some, and returns them.
+    pub fn compress_jpeg(&mut self) -> Result<&mut Jpeg> {
+        if self.jpeg.is_none() {
+            self.jpeg = Some(Jpeg::from_image(&self.clone().into_image())?);
+        }
+        Ok(self.jpeg.as_mut().unwrap())
+    }
+
+    /// Whether the index of `pixel` has a twin entry in the palette.
+    pub fn is_paired(&self, pixel: usize) -> bool {
+        self.palette
+            .as_ref()
+            .is_some_and(|palette| palette.is_paired(self.data[pixel] as usize))
+    }
+
+    /// Gives the most used colours of the palette a twin entry of the same
+    /// colour next to them, so the lowest bit of their index can be chosen
+    /// freely. Identical entries are merged first.
+    pub fn pair_palette(&mut self) {
+        let Some(palette) = &self.palette else {
+            return;
+        };
+
+        let mut entries = Vec::new();
+        let map = (0..palette.len())
+            .map(|index| {
+                let entry = palette.entry(index);
+                entries
+                    .iter()
+                    .position(|&known| known == entry)
+                    .unwrap_or_else(|| {
+                        entries.push(entry);
+                        entries.len() - 1
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let mut uses = vec![0usize; entries.len()];
+        for &index in &self.data {
+            if let Some(&entry) = map.get(index as usize) {
+                uses[entry] += 1;
+            }
+        }
+        let mut order = (0..entries.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&entry| Reverse(uses[entry]));
+
+        let pairs = entries.len().min(256 - entries.len());
+        let mut paired = Vec::new();
+        let mut moved = vec![0u8; entries.len()];
+        for (rank, &entry) in order.iter().enumerate() {
+            moved[entry] = paired.len() as u8;
+            paired.push(entries[entry]);
+            if rank < pairs {
+                paired.push(entries[entry]);
+            }
+        }
+
+        for index in &mut self.data {
+            *index = map.get(*index as usize).map_or(0, |&entry| moved[entry]);
+        }
+        self.palette = Some(Palette::from_entries(&paired));
+    }
+
+    /// Bytes of every sample, pixel by pixel.
+    pub fn samples(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Samples to change, dropping the JPEG coefficients which no longer
+    /// match them.
+    pub fn samples_mut(&mut self) -> &mut [u8] {
+        self.jpeg = None;
+        &mut self.data
+    }
+
+    /// Samples per pixel.
+    pub fn channels(&self) -> usize {
+        self.color.channel_count() as usize
+    }
+
+    /// Bytes per sample.
+    pub fn depth(&self) -> usize {
+        self.color.bytes_per_pixel() as usize / self.channels()
+    }
+
+    /// Samples per pixel carrying colour rather than alpha.
+    pub fn colour_channels(&self) -> usize {
+        self.channels() - self.has_alpha() as usize
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.color.has_alpha()
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.color, ColorType::Rgb32F | ColorType::Rgba32F)
+    }
+
+    /// Number of pixels.
+    pub fn len(&self) -> usize {
+        self.data.len() / self.color.bytes_per_pixel() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Whether `pixel` has an alpha sample of zero.
+    pub fn is_transparent(&self, pixel: usize) -> bool {
+        if !self.has_alpha() {
+            return false;
+        }
+        let alpha = self.sample(pixel, self.channels() - 1);
+        match self.is_float() {
+            true => f32::from_le_bytes(alpha.try_into().unwrap()) == 0.0,
+            false => alpha.iter().all(|&byte| byte == 0),
+        }
+    }
+
+    fn sample(&self, pixel: usize, channel: usize) -> &[u8] {
+        let at = (pixel * self.channels() + channel) * self.depth();
+        &self.data[at..at + self.depth()]
+    }
+
+    /// Makes every pixel fully opaque.
+    pub fn make_opaque(&mut self) {
+        if let Some(palette) = &mut self.palette {
+            palette.alpha.clear();
+        }
+        if !self.has_alpha() {
+            return;
+        }
+        let opaque = match self.is_float() {
+            true => 1f32.to_le_bytes().to_vec(),
+            false => vec![0xff; self.depth()],
+        };
+        let (depth, stride) = (self.depth(), self.color.bytes_per_pixel() as usize);
+        for pixel in self.data.chunks_mut(stride) {
+            pixel[stride - depth..].copy_from_slice(&opaque);
+        }
+    }
+
+    /// Gives every fully transparent pixel the colour of its nearest opaque
+    /// pixel, found by a breadth-first search from all opaque pixels at once.
+    pub fn recolour_transparent(&mut self) {
+        let (width, count) = (self.width as usize, self.len());
+        let stride = self.color.bytes_per_pixel() as usize;
+        let colour = self.colour_channels() * self.depth();
+
+        let mut reached = (0..count)
+            .map(|pixel| !self.is_transparent(pixel))
+            .collect::<Vec<_>>();
+        let mut queue = (0..count)
+            .filter(|&pixel| reached[pixel])
+            .collect::<VecDeque<_>>();
+
+        while let Some(pixel) = queue.pop_front() {
+            let (x, y) = (pixel % width, pixel / width);
+            let neighbours = [
+                (x > 0).then(|| pixel - 1),
+                (x + 1 < width).then(|| pixel + 1),
+                (y > 0).then(|| pixel - width),
+                (pixel + width < count).then(|| pixel + width),
+            ];
+            for next in neighbours.into_iter().flatten() {
+                if !reached[next] {
+                    reached[next] = true;
+                    self.data
+                        .copy_within(pixel * stride..pixel * stride + colour, next * stride);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    /// Converts back into an image of the same colour type, expanding the
+    /// palette of indexed images.
+    pub fn into_image(self) -> DynamicImage {
+        let (width, height) = (self.width, self.height);
+        if let Some(palette) = &self.palette {
+            let entry = |x, y| {
+                let index = self.data[y as usize * width as usize + x as usize];
+                palette.entry(index as usize)
+            };
+            return match palette.alpha.is_empty() {
+                true => DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                    let [r, g, b, _] = entry(x, y);
+                    image::Rgb([r, g, b])
+                })),
+                false => DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+                    image::Rgba(entry(x, y))
+                })),
+            };
+        }
+        let words = |data: &[u8]| {
+            data.chunks(2)
+                .map(|word| u16::from_le_bytes([word[0], word[1]]))
+                .collect::<Vec<_>>()
+        };
+        let floats = |data: &[u8]| {
+            data.chunks(4)
+                .map(|word| f32::from_le_bytes(word.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let data = self.data;
+
+        // The buffer length always matches the colour type and size.
+        match self.color {
+            ColorType::L8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+            }
+            ColorType::La8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+            }
+            ColorType::Rgb8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+            }
+            ColorType::L16 => {
+                ImageBuffer::from_raw(width, height, words(&data)).map(DynamicImage::ImageLuma16)
+            }
+            ColorType::La16 => {
+                ImageBuffer::from_raw(width, height, words(&data)).map(DynamicImage::ImageLumaA16)
+            }
+            ColorType::Rgb16 => {
+                ImageBuffer::from_raw(width, height, words(&data)).map(DynamicImage::ImageRgb16)
+            }
+            ColorType::Rgba16 => {
+                ImageBuffer::from_raw(width, height, words(&data)).map(DynamicImage::ImageRgba16)
+            }
+            ColorType::Rgb32F => {
+                ImageBuffer::from_raw(width, height, floats(&data)).map(DynamicImage::ImageRgb32F)
+            }
+            ColorType::Rgba32F => {
+                ImageBuffer::from_raw(width, height, floats(&data)).map(DynamicImage::ImageRgba32F)
+            }
+            _ => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        }
+        .unwrap()
+    }
+
+    /// Expands an indexed image to RGB, or RGBA when its palette has
+    /// transparent entries.
+    pub fn into_true_colour(mut self) -> Canvas {
+        match self.palette {
+            Some(_) => {
+                let chunks = std::mem::take(&mut self.chunks);
+                Canvas::from(self.into_image()).with_chunks(chunks)
+            }
+            None => self,
+        }
+    }
+
+    /// Converts the samples to `color`.
+    pub fn into_color(mut self, color: ColorType) -> Canvas {
+        if color == self.color && self.palette.is_none() {
+            return self;
+        }
+        let mut chunks = std::mem::take(&mut self.chunks);
+        // An ICC profile describes either grey or colour samples.
+        if (self.palette.is_some() || self.color.has_color()) != color.has_color() {
+            chunks.retain(|chunk| &chunk.kind != b"iCCP");
+        }
+        let image = self.into_image();
+        let canvas: Canvas = match color {
+            ColorType::L8 => image.into_luma8().into(),
+            ColorType::La8 => image.into_luma_alpha8().into(),
+            ColorType::Rgb8 => image.into_rgb8().into(),
+            ColorType::L16 => image.into_luma16().into(),
+            ColorType::La16 => image.into_luma_alpha16().into(),
+            ColorType::Rgb16 => image.into_rgb16().into(),
+            ColorType::Rgba16 => image.into_rgba16().into(),
+            ColorType::Rgb32F => image.into_rgb32f().into(),
+            ColorType::Rgba32F => image.into_rgba32f().into(),
+            _ => image.into_rgba8().into(),
+        };
+        canvas.with_chunks(chunks)
+    }
+
+    fn with_chunks(mut self, chunks: Vec<Chunk>) -> Canvas {
+        self.chunks = chunks;
+        self
+    }
+
+    /// Copy of the image converted to RGBA8.
+    pub fn to_rgba8(&self) -> RgbaImage {
+        self.clone().into_image().into_rgba8()
+    }
+}
+
+impl From<DynamicImage> for Canvas {
+    fn from(image: DynamicImage) -> Canvas {
+        let (width, height) = (image.width(), image.height());
+        let words = |data: &[u16]| data.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let floats = |data: &[f32]| data.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        let (color, data) = match image {
+            DynamicImage::ImageLuma8(image) => (ColorType::L8, image.into_raw()),
+            DynamicImage::ImageLumaA8(image) => (ColorType::La8, image.into_raw()),
+            DynamicImage::ImageRgb8(image) => (ColorType::Rgb8, image.into_raw()),
+            DynamicImage::ImageRgba8(image) => (ColorType::Rgba8, image.into_raw()),
+            DynamicImage::ImageLuma16(image) => (ColorType::L16, words(&image)),
+            DynamicImage::ImageLumaA16(image) => (ColorType::La16, words(&image)),
+            DynamicImage::ImageRgb16(image) => (ColorType::Rgb16, words(&image)),
+            DynamicImage::ImageRgba16(image) => (ColorType::Rgba16, words(&image)),
+            DynamicImage::ImageRgb32F(image) => (ColorType::Rgb32F, floats(&image)),
+            DynamicImage::ImageRgba32F(image) => (ColorType::Rgba32F, floats(&image)),
+            image => (ColorType::Rgba8, image.into_rgba8().into_raw()),
+        };
+
+        Canvas {
+            width,
+            height,
+            color,
+            data,
+            palette: None,
+            chunks: Vec::new(),
+            jpeg: None,
+        }
+    }
+}
+
+impl<P: Pixel> From<ImageBuffer<P, Vec<P::Subpixel>>> for Canvas
+where
+    DynamicImage: From<ImageBuffer<P, Vec<P::Subpixel>>>,
+{
+    fn from(image: ImageBuffer<P, Vec<P::Subpixel>>) -> Canvas {
+        DynamicImage::from(image).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, LumaA, Rgba};
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("subtxt-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Saves `canvas` as `file` and opens it again.
+    fn reopened(canvas: &Canvas, dir: &Path, file: &str) -> Canvas {
+        let path = dir.join(file);
+        canvas
+            .save(&path, ImageFormat::from_path(&path).unwrap())
+            .unwrap();
+        Canvas::open(&path).unwrap()
+    }
+
+    #[test]
+    fn deep_samples_are_kept() {
+        let dir = scratch("canvas-deep");
+        let canvas = Canvas::from(ImageBuffer::from_fn(17, 9, |x, y| {
+            Rgba([
+                x as u16 * 3001,
+                y as u16 * 7001,
+                1 + x as u16 * y as u16,
+                65535,
+            ])
+        }));
+        assert_eq!(canvas.color(), ColorType::Rgba16);
+        assert_eq!((canvas.channels(), canvas.depth()), (4, 2));
+        // Little endian whatever the platform.
+        assert_eq!(canvas.samples()[2..4], 0u16.to_le_bytes());
+        assert_eq!(canvas.samples()[8..12], [0xb9, 0x0b, 0, 0]);
+
+        for file in ["image.png", "image.tiff"] {
+            assert_eq!(reopened(&canvas, &dir, file), canvas, "{file}");
+        }
+        assert_eq!(Canvas::from(canvas.clone().into_image()), canvas);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn grey_and_alpha_is_kept() {
+        let dir = scratch("canvas-grey");
+        let canvas = Canvas::from(ImageBuffer::from_fn(17, 9, |x, y| {
+            LumaA([(x * 15) as u8, (y * 28) as u8])
+        }));
+        assert_eq!(canvas.color(), ColorType::La8);
+        assert_eq!((canvas.channels(), canvas.depth()), (2, 1));
+        assert_eq!(reopened(&canvas, &dir, "image.png"), canvas);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn float_samples_are_kept() {
+        let dir = scratch("canvas-float");
+        let canvas = Canvas::from(ImageBuffer::from_fn(17, 9, |x, y| {
+            Rgba([x as f32 / 16.0, y as f32 * 0.37, 1.5, 0.25])
+        }));
+        assert_eq!(canvas.color(), ColorType::Rgba32F);
+        assert_eq!((canvas.channels(), canvas.depth()), (4, 4));
+        assert_eq!(canvas.samples()[8..12], 1.5f32.to_le_bytes());
+        assert!(canvas.is_float());
+
+        assert_eq!(reopened(&canvas, &dir, "image.exr"), canvas);
+        assert_eq!(Canvas::from(canvas.clone().into_image()), canvas);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Indexed image using entry `at % 5` of a palette whose entries 1 and
+    /// 4 are the same colour, so entry 0 is used most.
+    fn indexed() -> Canvas {
+        let palette = Palette {
+            colours: vec![
+                [10, 20, 30],
+                [40, 50, 60],
+                [70, 80, 90],
+                [1, 2, 3],
+                [40, 50, 60],
+            ],
+            alpha: vec![255, 128, 0, 255, 128],
+        };
+        let indices = (0..17 * 9)
+            .map(|at| match at % 7 {
+                5 | 6 => 0,
+                at => at as u8,
+            })
+            .collect();
+        Canvas::indexed(17, 9, indices, palette)
+    }
+
+    #[test]
+    fn pairing_keeps_the_colours() {
+        let mut canvas = indexed();
+        let before = canvas.to_rgba8();
+        canvas.pair_palette();
+        assert_eq!(canvas.to_rgba8(), before);
+
+        let palette = canvas.palette().unwrap();
+        // The twin entries are merged before every colour is paired.
+        assert_eq!(palette.len(), 8);
+        assert!((0..palette.len()).all(|index| palette.is_paired(index)));
+        assert_eq!(palette.entry(0), [10, 20, 30, 255]);
+        assert!((0..canvas.len()).all(|pixel| canvas.is_paired(pixel)));
+    }
+
+    #[test]
+    fn pairing_a_full_palette_pairs_the_most_used() {
+        let palette = Palette {
+            colours: (0..=255).map(|index| [index, 0, 0]).collect(),
+            alpha: Vec::new(),
+        };
+        let indices = (0..64 * 64)
+            .map(|at| [7, 7, 7, (at % 256) as u8][at % 4])
+            .collect();
+        let mut canvas = Canvas::indexed(64, 64, indices, palette);
+        let before = canvas.to_rgba8();
+        canvas.pair_palette();
+        assert_eq!(canvas.to_rgba8(), before);
+
+        // No entry is left for a twin, so nothing is paired.
+        let palette = canvas.palette().unwrap();
+        assert_eq!(palette.len(), 256);
+        assert!(!(0..palette.len()).any(|index| palette.is_paired(index)));
+        assert_eq!(palette.entry(0), [7, 0, 0, 255]);
+    }
+
+    #[test]
+    fn palette_is_kept() {
+        let dir = scratch("canvas-palette");
+        let mut canvas = indexed();
+        canvas.pair_palette();
+
+        let reopened = reopened(&canvas, &dir, "image.png");
+        assert_eq!(reopened, canvas);
+        assert_eq!(reopened.color(), ColorType::L8);
+        assert_eq!(reopened.palette(), canvas.palette());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//! PNG chunks as an alternative to the pixels for carrying the payload.
+//!
+//! The payload, header included, is stored unchanged in a private `stXt`
+//! chunk, or base64 encoded under the keyword `Comment` in a compressed
+//! `zTXt` or `iTXt` text chunk that image viewers show as a comment. The
+//! pixels are not touched, so there is no capacity limit but nothing is
+//! hidden from anyone listing the chunks of the file.
+
+use crate::chunk::{Chunk, Place};
+use crate::{Canvas, Header, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Keyword of the text chunks holding a payload.
+const KEYWORD: &[u8] = b"Comment";
+
+/// Largest payload a chunk can hold.
+pub const MAX_LEN: usize = i32::MAX as usize;
+
+/// Where the payload is hidden.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Carrier {
+    /// Samples chosen by the [`crate::Mode`].
+    #[default]
+    Pixels,
+    /// Private ancillary `stXt` chunk.
+    Chunk,
+    /// Compressed Latin-1 text chunk.
+    ZTxt,
+    /// International text chunk, compressed.
+    ITxt,
+}
+
+impl Carrier {
+    fn kind(self) -> Option<[u8; 4]> {
+        match self {
+            Carrier::Pixels => None,
+            Carrier::Chunk => Some(*b"stXt"),
+            Carrier::ZTxt => Some(*b"zTXt"),
+            Carrier::ITxt => Some(*b"iTXt"),
+        }
+    }
+
+    /// Chunk holding `bytes`.
+    fn encode(self, bytes: &[u8]) -> Result<Chunk> {
+        let kind = self.kind().ok_or("pixels are not a chunk carrier")?;
+        let text = || deflate(BASE64_STANDARD.encode(bytes).as_bytes());
+        let data = match self {
+            Carrier::Chunk => bytes.to_vec(),
+            // Keyword and zlib compression.
+            Carrier::ZTxt => [KEYWORD, &[0, 0], &text()?].concat(),
+            // Keyword, compressed with zlib, no language tag and no
+            // translated keyword.
+            _ => [KEYWORD, &[0, 1, 0, 0, 0], &text()?].concat(),
+        };
+        Ok(Chunk::new(kind, data, Place::AfterData))
+    }
+
+    /// Payload held by `chunk`, if it is a carrier chunk holding one.
+    fn decode(chunk: &Chunk) -> Option<(Carrier, Vec<u8>)> {
+        let (carrier, bytes) = match &chunk.kind {
+            b"stXt" => (Carrier::Chunk, chunk.data.clone()),
+            b"zTXt" => (Carrier::ZTxt, decode_ztxt(&chunk.data)?),
+            b"iTXt" => (Carrier::ITxt, decode_itxt(&chunk.data)?),
+            _ => return None,
+        };
+        Header::is_present(&bytes).then_some((carrier, bytes))
+    }
+}
+
+impl fmt::Display for Carrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Carrier::Pixels => "pixels",
+            Carrier::Chunk => "private stXt chunk",
+            Carrier::ZTxt => "zTXt chunk",
+            Carrier::ITxt => "iTXt chunk",
+        })
+    }
+}
+
+impl FromStr for Carrier {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Carrier> {
+        match name {
+            "pixels" => Ok(Carrier::Pixels),
+            "chunk" => Ok(Carrier::Chunk),
+            "ztxt" => Ok(Carrier::ZTxt),
+            "itxt" => Ok(Carrier::ITxt),
+            _ => Err(format!("unknown carrier {name}").into()),
+        }
+    }
+}
+
+/// Finds a payload stored in the chunks of `canvas`, returning the carrier
+/// and the bytes starting with the [`Header`].
+pub fn find(canvas: &Canvas) -> Option<(Carrier, Vec<u8>)> {
+    canvas.chunks().iter().find_map(Carrier::decode)
+}
+
+/// Stores `bytes` in a chunk of `canvas`, replacing any payload chunk.
+pub fn write(canvas: &mut Canvas, carrier: Carrier, bytes: &[u8]) -> Result<()> {
+    if bytes.len() > MAX_LEN {
+        return Err("the payload is too large for a PNG chunk".into());
+    }
+    let chunk = carrier.encode(bytes)?;
+    remove(canvas);
+    canvas.chunks_mut().push(chunk);
+    Ok(())
+}
+
+/// Removes the chunks holding a payload from `canvas`.
+pub fn remove(canvas: &mut Canvas) {
+    canvas
+        .chunks_mut()
+        .retain(|chunk| Carrier::decode(chunk).is_none());
+}
+
+fn decode_ztxt(data: &[u8]) -> Option<Vec<u8>> {
+    let text = data.strip_prefix(KEYWORD)?.strip_prefix(&[0, 0])?;
+    BASE64_STANDARD.decode(inflate(text)?).ok()
+}
+
+fn decode_itxt(data: &[u8]) -> Option<Vec<u8>> {
+    let (&[compressed, 0], rest) = data
+        .strip_prefix(KEYWORD)?
+        .strip_prefix(&[0])?
+        .split_first_chunk()?
+    else {
+        return None;
+    };
+    // Skip the language tag and the translated keyword.
+    let text = rest.splitn(3, |&byte| byte == 0).nth(2)?;
+    let text = match compressed {
+        0 => text.to_vec(),
+        _ => inflate(text)?,
+    };
+    BASE64_STANDARD.decode(text).ok()
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data)
+        .take(MAX_LEN as u64)
+        .read_to_end(&mut out)
+        .ok()?;
+    Some(out)
+}
@@ -0,0 +1,122 @@
+//! Ancillary PNG chunks carried from the source image to the output.
+//!
+//! Every chunk is stored as
+//!
+//! | size | field                                  |
+//! |------|----------------------------------------|
+//! | 4    | data length, big-endian `u32`          |
+//! | 4    | chunk type, four ASCII letters         |
+//! | n    | data                                   |
+//! | 4    | big-endian CRC32 of the type and data  |
+//!
+//! Chunks describing how to render the image (gAMA, cHRM, sRGB, iCCP),
+//! its physical size (pHYs), text (tEXt, zTXt, iTXt), time stamp (tIME)
+//! and Exif data are copied unchanged, as are unknown chunks the PNG
+//! specification marks safe to copy. Chunks tied to the colour type or the
+//! palette (tRNS, bKGD, sBIT, hIST) and the frames of animated PNGs are
+//! dropped, the encoder writes what the new image needs.
+
+use crate::Result;
+
+/// Bytes every PNG file starts with.
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Where a chunk sits relative to the palette and the image data, which
+/// some chunk types must precede.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Place {
+    BeforePalette,
+    BeforeData,
+    AfterData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+    pub place: Place,
+}
+
+impl Chunk {
+    pub fn new(kind: [u8; 4], data: Vec<u8>, place: Place) -> Chunk {
+        Chunk { kind, data, place }
+    }
+
+    /// Whether the chunk stays valid once the pixels are rewritten.
+    pub fn is_kept(&self) -> bool {
+        match &self.kind {
+            b"tRNS" | b"bKGD" | b"sBIT" | b"hIST" => false,
+            b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"pHYs" | b"sPLT" | b"tEXt"
+            | b"zTXt" | b"iTXt" | b"tIME" | b"eXIf" => true,
+            kind => kind[0].is_ascii_lowercase() && kind[3].is_ascii_lowercase(),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&self.kind);
+        crc.update(&self.data);
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.kind);
+        out.extend_from_slice(&self.data);
+        out.extend_from_slice(&crc.finalize().to_be_bytes());
+    }
+}
+
+/// All chunks of the PNG file in `bytes`, critical ones included.
+pub fn read(bytes: &[u8]) -> Result<Vec<Chunk>> {
+    let mut rest = bytes
+        .strip_prefix(&SIGNATURE)
+        .ok_or("the image is not a PNG file")?;
+    let mut place = Place::BeforePalette;
+    let mut chunks = Vec::new();
+
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+        let kind: [u8; 4] = rest[4..8].try_into()?;
+        let data = rest.get(8..8 + len).ok_or("truncated PNG chunk")?;
+        match &kind {
+            b"PLTE" => place = Place::BeforeData,
+            b"IDAT" => place = Place::AfterData,
+            _ => {}
+        }
+        chunks.push(Chunk::new(kind, data.to_vec(), place));
+        rest = &rest[(12 + len).min(rest.len())..];
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(chunks)
+}
+
+/// Ancillary chunks of the PNG file in `bytes` worth copying to an output.
+pub fn ancillary(bytes: &[u8]) -> Result<Vec<Chunk>> {
+    let mut chunks = read(bytes)?;
+    chunks.retain(Chunk::is_kept);
+    Ok(chunks)
+}
+
+/// Copy of the PNG file in `png` with `chunks` added in their place: in
+/// front of the palette, the first image data or the end.
+pub fn insert(png: &[u8], chunks: &[Chunk]) -> Result<Vec<u8>> {
+    const PLACES: [Place; 3] = [Place::BeforePalette, Place::BeforeData, Place::AfterData];
+    let mut out = SIGNATURE.to_vec();
+    let mut written = 0;
+
+    for chunk in read(png)? {
+        let due = match &chunk.kind {
+            b"PLTE" => 1,
+            b"IDAT" => 2,
+            b"IEND" => 3,
+            _ => 0,
+        };
+        for &place in PLACES.get(written..due).unwrap_or_default() {
+            for extra in chunks.iter().filter(|extra| extra.place == place) {
+                extra.write(&mut out);
+            }
+        }
+        written = written.max(due);
+        chunk.write(&mut out);
+    }
+    Ok(out)
+}
@@ -0,0 +1,90 @@
+//! Compression of the payload before it is embedded.
+
+use crate::header::{FLAG_DEFLATE, FLAG_ZSTD};
+use crate::Result;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+const ZSTD_LEVEL: i32 = 19;
+const MAX_DECOMPRESSED: u64 = 1 << 30;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// Header flags recording this algorithm.
+    pub fn flags(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => FLAG_DEFLATE,
+            Compression::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    /// Algorithm recorded in the header `flags`.
+    pub fn from_flags(flags: u8) -> Result<Compression> {
+        match flags & (FLAG_DEFLATE | FLAG_ZSTD) {
+            0 => Ok(Compression::None),
+            FLAG_DEFLATE => Ok(Compression::Deflate),
+            FLAG_ZSTD => Ok(Compression::Zstd),
+            _ => Err("unsupported compression algorithm".into()),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Compression::None => return Ok(data.to_vec()),
+            Compression::Deflate => DeflateDecoder::new(data)
+                .take(MAX_DECOMPRESSED)
+                .read_to_end(&mut out)?,
+            Compression::Zstd => zstd::Decoder::new(data)?
+                .take(MAX_DECOMPRESSED)
+                .read_to_end(&mut out)?,
+        };
+        Ok(out)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Compression> {
+        match name {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression algorithm {name}").into()),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
@@ -0,0 +1,393 @@
+//! Encryption of the payload.
+//!
+//! With a passphrase the key is derived with Argon2id and the payload is
+//! sealed with ChaCha20-Poly1305. A payload encrypted with a passphrase
+//! starts with the parameters needed to open it again:
+//!
+//! | offset | size | field                                |
+//! |--------|------|--------------------------------------|
+//! | 0      | 16   | Argon2id salt                        |
+//! | 16     | 4    | memory cost in KiB, little-endian    |
+//! | 20     | 4    | number of iterations, little-endian  |
+//! | 24     | 4    | degree of parallelism, little-endian |
+//! | 28     | 12   | ChaCha20-Poly1305 nonce              |
+//! | 40     | ..   | ciphertext and 16 byte tag           |
+//!
+//! For recipients the payload is sealed with a random file key, which is
+//! wrapped once per recipient with a key agreed between a fresh X25519
+//! key pair and the recipient's public key. Keys use the same text
+//! encoding as [age](https://age-encryption.org), so `age-keygen` output
+//! works as identity file. As in age, keys agreeing on an all-zero secret
+//! are refused. The payload starts with the wrapped keys:
+//!
+//! | offset   | size | field                                       |
+//! |----------|------|---------------------------------------------|
+//! | 0        | 1    | number of recipients `n`                    |
+//! | 1        | 80n  | ephemeral public key and wrapped file key   |
+//! | 1 + 80n  | 12   | ChaCha20-Poly1305 nonce                     |
+//! | 13 + 80n | ..   | ciphertext and 16 byte tag                  |
+
+use crate::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const PARAMS_SIZE: usize = SALT_SIZE + 12 + NONCE_SIZE;
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 64;
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| format!("key derivation failed: {err}"))?;
+    Ok(key)
+}
+
+/// Encrypts `plain` with a key derived from `passphrase`.
+pub fn seal(plain: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let params = Params::default();
+    let key = derive_key(passphrase, &salt, params.clone())?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut sealed = Vec::with_capacity(PARAMS_SIZE + plain.len() + 16);
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&params.m_cost().to_le_bytes());
+    sealed.extend_from_slice(&params.t_cost().to_le_bytes());
+    sealed.extend_from_slice(&params.p_cost().to_le_bytes());
+    sealed.extend_from_slice(&nonce);
+    sealed.append(
+        &mut ChaCha20Poly1305::new(&key)
+            .encrypt(&nonce, plain)
+            .map_err(|_| "error encrypting text")?,
+    );
+    Ok(sealed)
+}
+
+/// Decrypts a payload produced by [`seal`].
+pub fn open(sealed: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if sealed.len() < PARAMS_SIZE {
+        return Err("encrypted text is truncated".into());
+    }
+
+    let (salt, rest) = sealed.split_at(SALT_SIZE);
+    let cost = |at: usize| u32::from_le_bytes(rest[at..at + 4].try_into().unwrap());
+    if cost(0) > MAX_M_COST || cost(4) > MAX_T_COST {
+        return Err("key derivation parameters are out of range".into());
+    }
+    let params = Params::new(cost(0), cost(4), cost(8), None)
+        .map_err(|err| format!("invalid key derivation parameters: {err}"))?;
+    let key = derive_key(passphrase, salt, params)?;
+    let nonce = Nonce::from_slice(&rest[12..12 + NONCE_SIZE]);
+
+    ChaCha20Poly1305::new(&key)
+        .decrypt(nonce, &rest[12 + NONCE_SIZE..])
+        .map_err(|_| "wrong passphrase or corrupted hidden text".into())
+}
+
+const PUBLIC_HRP: &str = "age";
+const SECRET_HRP: &str = "age-secret-key-";
+const STANZA_SIZE: usize = 32 + 48;
+const WRAP_INFO: &[u8] = b"subtxt X25519";
+
+/// Public key of someone allowed to read the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// Private key matching a [`Recipient`].
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn to_public(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Identity").field(&self.to_public()).finish()
+    }
+}
+
+fn decode_key(text: &str, hrp: &str) -> Result<[u8; 32]> {
+    let (found, data, variant) = bech32::decode(text).map_err(|err| format!("{err}"))?;
+    if found != hrp || variant != Variant::Bech32 {
+        return Err(format!("expected a key starting with {hrp}1").into());
+    }
+
+    Vec::<u8>::from_base32(&data)?
+        .try_into()
+        .map_err(|_| "invalid key length".into())
+}
+
+fn encode_key(key: &[u8], hrp: &str) -> String {
+    bech32::encode(hrp, key.to_base32(), Variant::Bech32).unwrap()
+}
+
+impl FromStr for Recipient {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(text: &str) -> Result<Recipient> {
+        let key = decode_key(text, PUBLIC_HRP)
+            .map_err(|err| format!("invalid recipient {text}: {err}"))?;
+        Ok(Recipient(PublicKey::from(key)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_key(self.0.as_bytes(), PUBLIC_HRP))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(text: &str) -> Result<Identity> {
+        let key = decode_key(&text.to_lowercase(), SECRET_HRP)
+            .map_err(|err| format!("invalid identity: {err}"))?;
+        Ok(Identity(StaticSecret::from(key)))
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_key(self.0.as_bytes(), SECRET_HRP).to_uppercase())
+    }
+}
+
+/// Parses keys listed one per line, skipping blank lines and `#` comments.
+pub fn parse_keys<T>(text: &str) -> Result<Vec<T>>
+where
+    T: FromStr<Err = Box<dyn std::error::Error>>,
+{
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+fn wrap_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .unwrap();
+    key
+}
+
+/// Encrypts `plain` so that any of `recipients` can open it.
+pub fn seal_for(plain: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>> {
+    let Ok(count) = u8::try_from(recipients.len()) else {
+        return Err("too many recipients".into());
+    };
+    if count == 0 {
+        return Err("at least one recipient is required".into());
+    }
+
+    let file_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut sealed = vec![count];
+    for Recipient(recipient) in recipients {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(
+                format!("invalid recipient {}: low order key", Recipient(*recipient)).into(),
+            );
+        }
+        let key = wrap_key(shared.as_bytes(), &ephemeral_public, recipient);
+
+        sealed.extend_from_slice(ephemeral_public.as_bytes());
+        sealed.append(
+            &mut ChaCha20Poly1305::new(&key)
+                .encrypt(&Nonce::default(), file_key.as_slice())
+                .map_err(|_| "error encrypting text")?,
+        );
+    }
+
+    sealed.extend_from_slice(&nonce);
+    sealed.append(
+        &mut ChaCha20Poly1305::new(&file_key)
+            .encrypt(&nonce, plain)
+            .map_err(|_| "error encrypting text")?,
+    );
+    Ok(sealed)
+}
+
+/// Decrypts a payload produced by [`seal_for`] with one of `identities`.
+pub fn open_with(sealed: &[u8], identities: &[Identity]) -> Result<Vec<u8>> {
+    let Some((&count, rest)) = sealed.split_first() else {
+        return Err("encrypted text is truncated".into());
+    };
+    let stanzas_len = count as usize * STANZA_SIZE;
+    if rest.len() < stanzas_len + NONCE_SIZE {
+        return Err("encrypted text is truncated".into());
+    }
+
+    let (stanzas, rest) = rest.split_at(stanzas_len);
+    let file_key = stanzas
+        .chunks(STANZA_SIZE)
+        .find_map(|stanza| {
+            let ephemeral = PublicKey::from(<[u8; 32]>::try_from(&stanza[..32]).unwrap());
+            identities.iter().find_map(|Identity(secret)| {
+                let shared = secret.diffie_hellman(&ephemeral);
+                // An all-zero secret is known to anyone; age refuses it too.
+                if !shared.was_contributory() {
+                    return None;
+                }
+                let key = wrap_key(shared.as_bytes(), &ephemeral, &PublicKey::from(secret));
+                ChaCha20Poly1305::new(&key)
+                    .decrypt(&Nonce::default(), &stanza[32..])
+                    .ok()
+            })
+        })
+        .ok_or("no identity matches a recipient of the hidden text")?;
+
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    ChaCha20Poly1305::new(Key::from_slice(&file_key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "corrupted hidden text".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_round_trip() {
+        let sealed = seal(b"hidden text", "correct horse").unwrap();
+        assert_eq!(open(&sealed, "correct horse").unwrap(), b"hidden text");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let sealed = seal(b"hidden text", "correct horse").unwrap();
+        let error = open(&sealed, "battery staple").unwrap_err();
+        assert!(error.to_string().contains("wrong passphrase"), "{error}");
+    }
+
+    #[test]
+    fn tampered_tag_is_refused() {
+        let mut sealed = seal(b"hidden text", "correct horse").unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        assert!(open(&sealed, "correct horse").is_err());
+    }
+
+    #[test]
+    fn truncated_parameters_are_refused() {
+        let sealed = seal(b"hidden text", "correct horse").unwrap();
+        let error = open(&sealed[..PARAMS_SIZE - 1], "correct horse").unwrap_err();
+        assert!(error.to_string().contains("truncated"), "{error}");
+    }
+
+    #[test]
+    fn costly_parameters_are_refused() {
+        let mut sealed = seal(b"hidden text", "correct horse").unwrap();
+        sealed[SALT_SIZE..SALT_SIZE + 4].copy_from_slice(&(MAX_M_COST + 1).to_le_bytes());
+        let error = open(&sealed, "correct horse").unwrap_err();
+        assert!(error.to_string().contains("out of range"), "{error}");
+    }
+
+    fn recipients() -> (Vec<Identity>, Vec<Recipient>) {
+        let identities = vec![Identity::generate(), Identity::generate()];
+        let recipients = identities.iter().map(Identity::to_public).collect();
+        (identities, recipients)
+    }
+
+    #[test]
+    fn every_recipient_opens() {
+        let (identities, recipients) = recipients();
+        let sealed = seal_for(b"hidden text", &recipients).unwrap();
+        for identity in identities {
+            assert_eq!(open_with(&sealed, &[identity]).unwrap(), b"hidden text");
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_as_text() {
+        let identity = Identity::generate();
+        let text = format!("# public key: {}\n{identity}\n", identity.to_public());
+        let parsed = parse_keys::<Identity>(&text).unwrap();
+        assert_eq!(parsed[0].to_public(), identity.to_public());
+        let recipient = identity.to_public().to_string().parse::<Recipient>();
+        assert_eq!(recipient.unwrap(), identity.to_public());
+    }
+
+    #[test]
+    fn wrong_identity_is_refused() {
+        let (_, recipients) = recipients();
+        let sealed = seal_for(b"hidden text", &recipients).unwrap();
+        let error = open_with(&sealed, &[Identity::generate()]).unwrap_err();
+        assert!(error.to_string().contains("no identity matches"), "{error}");
+    }
+
+    #[test]
+    fn tampered_stanza_is_refused() {
+        let (identities, recipients) = recipients();
+        let mut sealed = seal_for(b"hidden text", &recipients[..1]).unwrap();
+        sealed[1 + STANZA_SIZE - 1] ^= 1;
+        assert!(open_with(&sealed, &identities[..1]).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_refused() {
+        let (identities, recipients) = recipients();
+        let mut sealed = seal_for(b"hidden text", &recipients).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        let error = open_with(&sealed, &identities).unwrap_err();
+        assert!(error.to_string().contains("corrupted"), "{error}");
+    }
+
+    #[test]
+    fn low_order_recipient_is_refused() {
+        let zero = Recipient(PublicKey::from([0; 32]));
+        assert!(seal_for(b"hidden text", &[zero]).is_err());
+    }
+
+    #[test]
+    fn stanza_with_an_all_zero_secret_is_refused() {
+        // A stanza anyone can write: the ephemeral key is a low order
+        // point, so the secret agreed with any identity is all zero.
+        let identity = Identity::generate();
+        let ephemeral = PublicKey::from([0; 32]);
+        let key = wrap_key(&[0; 32], &ephemeral, &identity.to_public().0);
+        let file_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut sealed = vec![1];
+        sealed.extend_from_slice(ephemeral.as_bytes());
+        sealed.append(
+            &mut ChaCha20Poly1305::new(&key)
+                .encrypt(&Nonce::default(), file_key.as_slice())
+                .unwrap(),
+        );
+        sealed.extend_from_slice(&[0; NONCE_SIZE]);
+        sealed.append(
+            &mut ChaCha20Poly1305::new(&file_key)
+                .encrypt(&Nonce::default(), &b"forged"[..])
+                .unwrap(),
+        );
+        assert!(open_with(&sealed, &[identity]).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Reed-Solomon error correction of the payload.
+//!
+//! The payload is cut into blocks of at most `255 - parity` bytes, and every
+//! block gets `parity` check bytes computed over GF(2^8) with the primitive
+//! polynomial `x^8 + x^4 + x^3 + x^2 + 1`. A block is repaired as long as no
+//! more than `parity / 2` of its bytes are damaged. The blocks are stored
+//! interleaved, the first byte of every block, then the second ones and so
+//! on, so a damaged run of samples spreads over many blocks.
+
+use crate::Result;
+
+/// Bytes of a full block, data and check bytes.
+const BLOCK: usize = 255;
+
+/// Powers of the generator `α`, twice over so products need no modulo,
+/// and the logarithm of every non-zero element.
+const TABLES: ([u8; 512], [u8; 256]) = tables();
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let (mut exp, mut log) = ([0; 512], [0; 256]);
+    let mut value = 1u16;
+    let mut power = 0;
+    while power < 255 {
+        exp[power] = value as u8;
+        exp[power + 255] = value as u8;
+        log[value as usize] = power as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+        power += 1;
+    }
+    (exp, log)
+}
+
+fn exp(power: usize) -> u8 {
+    TABLES.0[power % 255]
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    match (a, b) {
+        (0, _) | (_, 0) => 0,
+        _ => TABLES.0[TABLES.1[a as usize] as usize + TABLES.1[b as usize] as usize],
+    }
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    match a {
+        0 => 0,
+        _ => TABLES.0[TABLES.1[a as usize] as usize + 255 - TABLES.1[b as usize] as usize],
+    }
+}
+
+/// Value at `x` of the polynomial with `coefficients`, lowest power first.
+fn eval(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |value, &coefficient| mul(value, x) ^ coefficient)
+}
+
+/// Largest payload whose encoding with `parity` check bytes per block
+/// fits in `len` bytes.
+pub fn capacity(len: usize, parity: u8) -> usize {
+    let parity = parity as usize;
+    len / BLOCK * (BLOCK - parity) + (len % BLOCK).saturating_sub(parity)
+}
+
+/// Lengths of the blocks holding `len` encoded bytes.
+fn block_lens(len: usize) -> impl Iterator<Item = usize> {
+    let count = len.div_ceil(BLOCK);
+    (0..count).map(move |block| match block + 1 == count {
+        true => len - BLOCK * block,
+        false => BLOCK,
+    })
+}
+
+/// Appends `parity` check bytes to every block of `data` and interleaves
+/// the blocks.
+pub fn encode(data: &[u8], parity: u8) -> Result<Vec<u8>> {
+    if !(1..BLOCK).contains(&(parity as usize)) {
+        return Err("error correction needs 1 to 254 check bytes per block".into());
+    }
+    let parity = parity as usize;
+    // Generator polynomial (x - α^0)(x - α^1)…, highest power first.
+    let mut generator = vec![1];
+    for root in 0..parity {
+        generator.push(0);
+        for at in (1..generator.len()).rev() {
+            generator[at] ^= mul(generator[at - 1], exp(root));
+        }
+    }
+
+    let blocks = data
+        .chunks(BLOCK - parity)
+        .map(|chunk| {
+            // Remainder of the division of chunk·x^parity by the generator.
+            let mut remainder = vec![0; parity];
+            for &byte in chunk {
+                let factor = byte ^ remainder[0];
+                remainder.rotate_left(1);
+                remainder[parity - 1] = 0;
+                for (check, &term) in remainder.iter_mut().zip(&generator[1..]) {
+                    *check ^= mul(term, factor);
+                }
+            }
+            [chunk, &remainder].concat()
+        })
+        .collect::<Vec<_>>();
+    Ok(interleave(&blocks))
+}
+
+/// Repairs and strips the check bytes of data written by [`encode`],
+/// returning the data and the number of bytes repaired.
+pub fn decode(bytes: &[u8], parity: u8) -> Result<(Vec<u8>, usize)> {
+    let mut blocks = deinterleave(bytes);
+    if blocks
+        .last()
+        .is_some_and(|block| block.len() <= parity as usize)
+    {
+        return Err("payload corrupted: the error correction blocks are truncated".into());
+    }
+    let mut repaired = 0;
+    for block in &mut blocks {
+        repaired += correct(block, parity as usize)
+            .ok_or("payload corrupted: too many errors to correct")?;
+        block.truncate(block.len() - parity as usize);
+    }
+    Ok((blocks.concat(), repaired))
+}
+
+fn interleave(blocks: &[Vec<u8>]) -> Vec<u8> {
+    let longest = blocks.first().map_or(0, Vec::len);
+    (0..longest)
+        .flat_map(|at| {
+            blocks
+                .iter()
+                .filter_map(move |block| block.get(at).copied())
+        })
+        .collect()
+}
+
+fn deinterleave(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let lens = block_lens(bytes.len()).collect::<Vec<_>>();
+    let mut blocks = vec![Vec::new(); lens.len()];
+    let mut bytes = bytes.iter().copied();
+    for at in 0..BLOCK {
+        for (block, &len) in blocks.iter_mut().zip(&lens) {
+            if at < len {
+                block.extend(bytes.next());
+            }
+        }
+    }
+    blocks
+}
+
+/// Repairs `block` in place, returning the number of bytes repaired, or
+/// `None` when the damage exceeds what `parity` check bytes can repair.
+fn correct(block: &mut [u8], parity: usize) -> Option<usize> {
+    // Byte `at` is the coefficient of x^(len - 1 - at).
+    let len = block.len();
+    let syndromes = |block: &[u8]| {
+        (0..parity)
+            .map(|root| {
+                block
+                    .iter()
+                    .fold(0, |value, &byte| mul(value, exp(root)) ^ byte)
+            })
+            .collect::<Vec<_>>()
+    };
+    let syndrome = syndromes(block);
+    if syndrome.iter().all(|&s| s == 0) {
+        return Some(0);
+    }
+
+    // Berlekamp-Massey: the error locator, lowest power first.
+    let (mut locator, mut previous) = (vec![1u8], vec![1u8]);
+    let (mut errors, mut shift, mut last) = (0, 1, 1u8);
+    for n in 0..parity {
+        let discrepancy = (1..=errors).fold(syndrome[n], |d, i| {
+            d ^ mul(locator.get(i).copied().unwrap_or(0), syndrome[n - i])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let factor = div(discrepancy, last);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (at, &term) in previous.iter().enumerate() {
+            next[at + shift] ^= mul(factor, term);
+        }
+        if 2 * errors <= n {
+            previous = std::mem::replace(&mut locator, next);
+            errors = n + 1 - errors;
+            last = discrepancy;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    if 2 * errors > parity {
+        return None;
+    }
+
+    // Forney: the error evaluator, then the size of every error.
+    let mut evaluator = vec![0u8; parity];
+    for (i, &s) in syndrome.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate().take(parity - i) {
+            evaluator[i + j] ^= mul(s, l);
+        }
+    }
+    let derivative = locator
+        .iter()
+        .enumerate()
+        .map(|(power, &term)| if power % 2 == 1 { term } else { 0 })
+        .skip(1)
+        .collect::<Vec<_>>();
+
+    // Chien search for the roots of the locator, the inverse positions.
+    let mut found = 0;
+    for (at, byte) in block.iter_mut().enumerate() {
+        let power = len - 1 - at;
+        let inverse = exp(255 - power % 255);
+        if eval(&locator, inverse) != 0 {
+            continue;
+        }
+        let size = div(
+            mul(exp(power), eval(&evaluator, inverse)),
+            eval(&derivative, inverse),
+        );
+        *byte ^= size;
+        found += 1;
+    }
+
+    (found == errors && syndromes(block).iter().all(|&s| s == 0)).then_some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut data = vec![0; len];
+        ChaCha20Rng::seed_from_u64(seed).fill(&mut data[..]);
+        data
+    }
+
+    /// Encodes `data` and damages `count` distinct bytes of every block,
+    /// check bytes included.
+    fn damaged(data: &[u8], parity: u8, count: usize, seed: u64) -> Vec<u8> {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let mut blocks = deinterleave(&encode(data, parity).unwrap());
+        for block in &mut blocks {
+            let positions = rand::seq::index::sample(&mut rng, block.len(), count);
+            for at in positions {
+                block[at] ^= rng.gen_range(1..=255);
+            }
+        }
+        interleave(&blocks)
+    }
+
+    #[test]
+    fn round_trip() {
+        for parity in [2, 16, 32, 64, 128, 192] {
+            for len in [0, 1, 200, 700] {
+                let data = random(len, parity as u64);
+                let encoded = encode(&data, parity).unwrap();
+                assert_eq!(
+                    encoded.len() - len,
+                    len.div_ceil(BLOCK - parity as usize) * parity as usize
+                );
+                assert_eq!(capacity(encoded.len(), parity), len);
+                assert_eq!(decode(&encoded, parity).unwrap(), (data, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn parity_outside_the_block_is_rejected() {
+        assert!(encode(b"text", 0).is_err());
+        assert!(encode(b"text", 255).is_err());
+    }
+
+    #[test]
+    fn half_the_parity_is_repaired_per_block() {
+        for parity in [2, 16, 32, 64, 128] {
+            let data = random(700, parity as u64);
+            let blocks = data.len().div_ceil(BLOCK - parity as usize);
+            let bytes = damaged(&data, parity, parity as usize / 2, 7);
+            let (decoded, repaired) = decode(&bytes, parity).unwrap();
+            assert_eq!(decoded, data);
+            assert_eq!(repaired, blocks * parity as usize / 2);
+        }
+    }
+
+    #[test]
+    fn one_error_more_is_rejected() {
+        // Two check bytes cannot tell two errors from one in another
+        // codeword, so only larger parities are checked.
+        for parity in [16, 32, 64, 128] {
+            let data = random(700, parity as u64);
+            for seed in 0..4 {
+                let bytes = damaged(&data, parity, parity as usize / 2 + 1, seed);
+                assert!(decode(&bytes, parity).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn burst_is_spread_over_the_blocks() {
+        let (data, parity) = (random(1000, 1), 32);
+        let mut bytes = encode(&data, parity).unwrap();
+        let blocks = data.len().div_ceil(BLOCK - parity as usize);
+        // A run of damaged bytes longer than any block could repair alone.
+        let burst = blocks * parity as usize / 2;
+        for byte in &mut bytes[100..100 + burst] {
+            *byte = !*byte;
+        }
+        assert_eq!(decode(&bytes, parity).unwrap(), (data, burst));
+    }
+
+    #[test]
+    fn truncated_blocks_are_rejected() {
+        // A last block no longer than its check bytes holds no data.
+        let bytes = encode(&random(300, 2), 32).unwrap();
+        let error = decode(&bytes[..BLOCK + 20], 32).unwrap_err();
+        assert!(error.to_string().contains("truncated"));
+    }
+}
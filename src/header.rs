@@ -0,0 +1,205 @@
+//! Header written in front of every hidden payload.
+//!
+//! The layout is fixed and independent of the platform that wrote it:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic bytes `STXT`                      |
+//! | 4      | 1    | format version                          |
+//! | 5      | 1    | flags                                   |
+//! | 6      | 1    | embedding mode                          |
+//! | 7      | 1    | embedding mode parameter                |
+//! | 8      | 6    | payload length, little-endian           |
+//! | 14     | 1    | error correction check bytes per block  |
+//! | 15     | 1    | reserved, zero                          |
+//!
+//! Flags describe how the payload following the header was transformed;
+//! see the `FLAG_*` constants. Payloads with error correction start with
+//! [`CHECK_SIZE`] check bytes protecting the header, so a damaged header is
+//! repaired before it is read.
+
+use crate::{ecc, Mode, Result};
+
+/// Magic bytes that open every payload.
+pub const MAGIC: [u8; 4] = *b"STXT";
+
+/// Format version written by this crate.
+pub const VERSION: u8 = 1;
+
+/// Payloads must be shorter than this to fit the 6-byte length.
+pub const MAX_LEN: u64 = 1 << 48;
+
+/// Reed-Solomon check bytes of the header in front of a payload with error
+/// correction, counted in the payload length.
+pub const CHECK_SIZE: usize = 16;
+
+/// The payload is sealed with a passphrase, see [`crate::crypto`].
+pub const FLAG_ENCRYPTED: u8 = 1 << 0;
+
+/// The payload is sealed for public key recipients, see [`crate::crypto`].
+pub const FLAG_RECIPIENTS: u8 = 1 << 1;
+
+/// The payload starts with a little-endian CRC32 of the bytes after it.
+pub const FLAG_CHECKSUM: u8 = 1 << 2;
+
+/// The payload was compressed with deflate, see [`crate::compress`].
+pub const FLAG_DEFLATE: u8 = 1 << 3;
+
+/// The payload was compressed with zstd, see [`crate::compress`].
+pub const FLAG_ZSTD: u8 = 1 << 4;
+
+/// The data is preceded by a metadata record, see [`crate::meta`].
+pub const FLAG_METADATA: u8 = 1 << 5;
+
+/// The data is a container of several files, see [`crate::archive`].
+pub const FLAG_ARCHIVE: u8 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    /// Mode the payload was embedded with.
+    pub mode: Mode,
+    /// Number of payload bytes following the header.
+    pub len: u64,
+    /// Reed-Solomon check bytes per block of the payload, see
+    /// [`crate::ecc`], none when zero.
+    pub ecc: u8,
+}
+
+impl Header {
+    /// Encoded size of the header in bytes.
+    pub const SIZE: usize = 16;
+
+    pub fn new(len: u64) -> Header {
+        Header {
+            version: VERSION,
+            flags: 0,
+            mode: Mode::default(),
+            len,
+            ecc: 0,
+        }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Encodes the header, failing when the length does not fit in it.
+    pub fn to_bytes(&self) -> Result<[u8; Header::SIZE]> {
+        if self.len >= MAX_LEN {
+            return Err(format!("payload of {} bytes is too large to hide", self.len).into());
+        }
+        let mut bytes = [0; Header::SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.flags;
+        (bytes[6], bytes[7]) = self.mode.to_header();
+        bytes[8..14].copy_from_slice(&self.len.to_le_bytes()[..6]);
+        bytes[14] = self.ecc;
+        Ok(bytes)
+    }
+
+    /// Check bytes of the header written in front of the payload when it
+    /// has error correction.
+    pub fn check_bytes(&self) -> Result<Vec<u8>> {
+        let protected = ecc::encode(&self.to_bytes()?, CHECK_SIZE as u8)?;
+        Ok(protected[Header::SIZE..].to_vec())
+    }
+
+    /// The header at the start of `bytes`, repaired with the check bytes
+    /// following it when it announces error correction.
+    pub fn repair(bytes: &[u8]) -> Vec<u8> {
+        let protected = bytes.get(..Header::SIZE + CHECK_SIZE);
+        match protected.map(|protected| ecc::decode(protected, CHECK_SIZE as u8)) {
+            Some(Ok((header, _))) if header[..4] == MAGIC && header[14] != 0 => header,
+            _ => bytes.to_vec(),
+        }
+    }
+
+    /// Whether `bytes` start with [`MAGIC`].
+    pub fn is_present(bytes: &[u8]) -> bool {
+        bytes.len() >= Header::SIZE && bytes[..4] == MAGIC
+    }
+
+    /// Parses a header, rejecting data that does not start with [`MAGIC`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Header> {
+        if !Header::is_present(bytes) {
+            return Err("no hidden text found in the image".into());
+        }
+
+        let version = bytes[4];
+        if version != VERSION {
+            return Err(format!("unsupported payload version {version}").into());
+        }
+
+        Ok(Header {
+            version,
+            flags: bytes[5],
+            mode: Mode::from_header(bytes[6], bytes[7])?,
+            len: u64::from_le_bytes([&bytes[8..14], &[0, 0]].concat().try_into().unwrap()),
+            ecc: bytes[14],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            flags: FLAG_CHECKSUM | FLAG_ZSTD,
+            mode: Mode::Lsb {
+                bits: 2,
+                alpha: true,
+            },
+            ecc: 32,
+            ..Header::new(MAX_LEN - 1)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = header().to_bytes().unwrap();
+        assert_eq!(bytes[..4], MAGIC);
+        assert_eq!(bytes[15], 0);
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), header());
+    }
+
+    #[test]
+    fn length_beyond_six_bytes_is_refused() {
+        let header = Header::new(MAX_LEN);
+        let error = header.to_bytes().unwrap_err();
+        assert!(error.to_string().contains("too large"), "{error}");
+    }
+
+    #[test]
+    fn missing_magic_is_refused() {
+        let mut bytes = header().to_bytes().unwrap();
+        bytes[0] = b's';
+        assert!(!Header::is_present(&bytes));
+        assert!(Header::from_bytes(&bytes).is_err());
+        assert!(Header::from_bytes(&MAGIC).is_err());
+    }
+
+    #[test]
+    fn unknown_version_is_refused() {
+        let mut bytes = header().to_bytes().unwrap();
+        bytes[4] = VERSION + 1;
+        let error = Header::from_bytes(&bytes).unwrap_err();
+        assert!(
+            error.to_string().contains("unsupported payload version"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn damaged_header_is_repaired() {
+        let header = header();
+        let mut bytes = header.to_bytes().unwrap().to_vec();
+        bytes.extend(header.check_bytes().unwrap());
+        bytes[9] ^= 0xff;
+        assert_eq!(Header::from_bytes(&Header::repair(&bytes)).unwrap(), header);
+    }
+}
@@ -0,0 +1,960 @@
+//! Baseline JPEG images as quantised DCT coefficients, and the jpeg mode
+//! hiding the payload in them.
+//!
+//! Decoding stops at the quantised coefficients and encoding writes them
+//! back with the standard Huffman tables of the JPEG specification, so an
+//! image opened and saved again keeps every coefficient, and the hidden
+//! bits with them. Progressive and arithmetic coded images are not read,
+//! they are compressed anew from their pixels by [`Jpeg::from_image`].
+//!
+//! The jpeg mode hides one bit in every non-zero AC coefficient in the
+//! manner of F4: positive coefficients hold their lowest bit, negative
+//! ones its inverse. A coefficient of the wrong parity moves one step
+//! towards zero. When it reaches zero the reader skips it, so the bit is
+//! written again into the next coefficient. With a key the coefficients
+//! are visited in the keyed order of [`crate::mode`], otherwise block by
+//! block.
+
+use crate::mode::{self, Order};
+use crate::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+
+/// Quality of the images compressed by [`Jpeg::from_image`].
+pub const QUALITY: u8 = 90;
+
+/// AC coefficients of every block, the DC coefficient is left alone.
+const AC: usize = 63;
+
+/// Coefficients and the tables needed to write them back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jpeg {
+    width: u16,
+    height: u16,
+    /// Quantisation tables by id, in zigzag order.
+    tables: [Option<[u16; 64]>; 4],
+    components: Vec<Component>,
+    /// APPn and COM segments, written back unchanged.
+    segments: Vec<(u8, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    table: u8,
+    /// Blocks per row, padded to whole MCUs.
+    wide: usize,
+    /// Coefficients of every block in zigzag order, row by row.
+    blocks: Vec<[i16; 64]>,
+}
+
+impl Jpeg {
+    /// Reads the coefficients of the baseline JPEG file in `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Jpeg> {
+        let mut reader = Reader { bytes, at: 0 };
+        if reader.take(2)? != [0xFF, 0xD8] {
+            return Err("the image is not a JPEG file".into());
+        }
+        let mut jpeg = Jpeg {
+            width: 0,
+            height: 0,
+            tables: [None; 4],
+            components: Vec::new(),
+            segments: Vec::new(),
+        };
+        let mut huffman: [[Option<Huffman>; 4]; 2] = Default::default();
+        let mut interval = 0;
+
+        loop {
+            let marker = reader.marker()?;
+            match marker {
+                0xD9 => break,
+                0x01 | 0xD0..=0xD8 => continue,
+                _ => {}
+            }
+            let segment = reader.segment()?;
+            match marker {
+                0xC0 | 0xC1 => jpeg.read_frame(segment)?,
+                0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                    return Err("only baseline JPEG images are supported".into())
+                }
+                0xC4 => read_huffman(segment, &mut huffman)?,
+                0xDB => jpeg.read_tables(segment)?,
+                0xDD => interval = u16::from_be_bytes(segment.try_into()?) as usize,
+                0xDA => {
+                    reader.at += jpeg.read_scan(segment, &bytes[reader.at..], &huffman, interval)?
+                }
+                0xE0..=0xEF | 0xFE => jpeg.segments.push((marker, segment.to_vec())),
+                _ => {}
+            }
+        }
+        if jpeg.components.is_empty() {
+            return Err("the JPEG image has no frame".into());
+        }
+        Ok(jpeg)
+    }
+
+    /// Compresses `image` at [`QUALITY`] and reads the coefficients back.
+    pub fn from_image(image: &DynamicImage) -> Result<Jpeg> {
+        let mut bytes = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut bytes, QUALITY);
+        match image.color().has_color() {
+            true => encoder.encode_image(&image.to_rgb8())?,
+            false => encoder.encode_image(&image.to_luma8())?,
+        }
+        Jpeg::decode(&bytes)
+    }
+
+    fn read_frame(&mut self, segment: &[u8]) -> Result<()> {
+        let [precision, h0, h1, w0, w1, count, ref rest @ ..] = *segment else {
+            return Err("corrupt JPEG frame header".into());
+        };
+        if precision != 8 {
+            return Err("only 8-bit JPEG images are supported".into());
+        }
+        self.height = u16::from_be_bytes([h0, h1]);
+        self.width = u16::from_be_bytes([w0, w1]);
+        if self.width == 0 || self.height == 0 || !(1..=4).contains(&count) {
+            return Err("unsupported JPEG frame header".into());
+        }
+        let specs = rest
+            .get(..3 * count as usize)
+            .ok_or("corrupt JPEG frame header")?;
+
+        self.components = specs
+            .chunks(3)
+            .map(|spec| Component {
+                id: spec[0],
+                // A single component is never interleaved, its sampling
+                // factors make no difference.
+                h: if count == 1 {
+                    1
+                } else {
+                    (spec[1] >> 4).max(1) as usize
+                },
+                v: if count == 1 {
+                    1
+                } else {
+                    (spec[1] & 15).max(1) as usize
+                },
+                table: spec[2] & 3,
+                wide: 0,
+                blocks: Vec::new(),
+            })
+            .collect();
+        let (mcus_x, mcus_y) = self.mcus();
+        for component in &mut self.components {
+            component.wide = mcus_x * component.h;
+            component.blocks = vec![[0; 64]; component.wide * mcus_y * component.v];
+        }
+        Ok(())
+    }
+
+    fn read_tables(&mut self, mut segment: &[u8]) -> Result<()> {
+        while let Some((&spec, rest)) = segment.split_first() {
+            let wide = spec >> 4 != 0;
+            let size = if wide { 128 } else { 64 };
+            let values = rest.get(..size).ok_or("corrupt JPEG quantisation table")?;
+            let mut table = [0; 64];
+            for (k, q) in table.iter_mut().enumerate() {
+                *q = match wide {
+                    true => u16::from_be_bytes([values[2 * k], values[2 * k + 1]]),
+                    false => values[k] as u16,
+                };
+            }
+            self.tables[spec as usize & 3] = Some(table);
+            segment = &rest[size..];
+        }
+        Ok(())
+    }
+
+    /// Decodes the scan whose header is `segment` from the entropy coded
+    /// `data` following it, returning the length of the data.
+    fn read_scan(
+        &mut self,
+        segment: &[u8],
+        data: &[u8],
+        huffman: &[[Option<Huffman>; 4]; 2],
+        interval: usize,
+    ) -> Result<usize> {
+        let corrupt = "corrupt JPEG scan header";
+        let (&count, rest) = segment.split_first().ok_or(corrupt)?;
+        let specs = rest.get(..2 * count as usize).ok_or(corrupt)?;
+        if rest.get(2 * count as usize..) != Some(&[0, 63, 0]) {
+            return Err("only baseline JPEG images are supported".into());
+        }
+        let mut scan = Vec::new();
+        for spec in specs.chunks(2) {
+            let component = self
+                .components
+                .iter()
+                .position(|component| component.id == spec[0])
+                .ok_or(corrupt)?;
+            let dc = huffman[0][spec[1] as usize >> 4 & 3].as_ref();
+            let ac = huffman[1][spec[1] as usize & 3].as_ref();
+            scan.push((component, dc.ok_or(corrupt)?, ac.ok_or(corrupt)?));
+        }
+
+        let (intervals, len) = unstuff(data);
+        let single = scan.len() == 1;
+        let mcus = self.scan_mcus(single.then(|| scan[0].0));
+        let mut bits = Bits::new(intervals.first().map_or(&[], Vec::as_slice));
+        let mut predictions = vec![0; scan.len()];
+
+        for mcu in 0..mcus {
+            if interval > 0 && mcu > 0 && mcu % interval == 0 {
+                let next = intervals.get(mcu / interval).map_or(&[][..], Vec::as_slice);
+                bits = Bits::new(next);
+                predictions.fill(0);
+            }
+            for (&(component, dc, ac), prediction) in scan.iter().zip(&mut predictions) {
+                for block in self.mcu_blocks(component, mcu, single) {
+                    let block = &mut self.components[component].blocks[block];
+                    decode_block(&mut bits, dc, ac, prediction, block)?;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Writes the coefficients as a baseline JPEG file.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![0xFF, 0xD8];
+        for (marker, data) in &self.segments {
+            write_segment(&mut out, *marker, data)?;
+        }
+
+        let mut extended = false;
+        for (id, table) in self.tables.iter().enumerate() {
+            let Some(table) = table else {
+                continue;
+            };
+            let wide = table.iter().any(|&q| q > 255);
+            extended |= wide;
+            let mut data = vec![(wide as u8) << 4 | id as u8];
+            for &q in table {
+                match wide {
+                    true => data.extend_from_slice(&q.to_be_bytes()),
+                    false => data.push(q as u8),
+                }
+            }
+            write_segment(&mut out, 0xDB, &data)?;
+        }
+
+        let mut frame = vec![8];
+        frame.extend_from_slice(&self.height.to_be_bytes());
+        frame.extend_from_slice(&self.width.to_be_bytes());
+        frame.push(self.components.len() as u8);
+        for component in &self.components {
+            let sampling = (component.h as u8) << 4 | component.v as u8;
+            frame.extend_from_slice(&[component.id, sampling, component.table]);
+        }
+        // 16-bit quantisation tables need the extended sequential frame.
+        write_segment(&mut out, if extended { 0xC1 } else { 0xC0 }, &frame)?;
+
+        let huffman = [
+            (0x00, &LUMA_DC_COUNTS, &LUMA_DC_SYMBOLS[..]),
+            (0x10, &LUMA_AC_COUNTS, &LUMA_AC_SYMBOLS[..]),
+            (0x01, &CHROMA_DC_COUNTS, &CHROMA_DC_SYMBOLS[..]),
+            (0x11, &CHROMA_AC_COUNTS, &CHROMA_AC_SYMBOLS[..]),
+        ];
+        for (class, counts, symbols) in huffman {
+            write_segment(&mut out, 0xC4, &[&[class], &counts[..], symbols].concat())?;
+        }
+        let tables = huffman.map(|(_, counts, symbols)| Huffman::new(counts, symbols));
+        let [luma_dc, luma_ac, chroma_dc, chroma_ac] = tables;
+        let (luma, chroma) = ((&luma_dc?, &luma_ac?), (&chroma_dc?, &chroma_ac?));
+
+        let mut scan = vec![self.components.len() as u8];
+        for (at, component) in self.components.iter().enumerate() {
+            scan.extend_from_slice(&[component.id, if at == 0 { 0x00 } else { 0x11 }]);
+        }
+        scan.extend_from_slice(&[0, 63, 0]);
+        write_segment(&mut out, 0xDA, &scan)?;
+
+        let single = self.components.len() == 1;
+        let mut writer = BitWriter {
+            out,
+            bits: 0,
+            count: 0,
+        };
+        let mut predictions = vec![0; self.components.len()];
+        for mcu in 0..self.scan_mcus(single.then_some(0)) {
+            for (component, prediction) in predictions.iter_mut().enumerate() {
+                let (dc, ac) = if component == 0 { luma } else { chroma };
+                for block in self.mcu_blocks(component, mcu, single) {
+                    let block = &self.components[component].blocks[block];
+                    encode_block(&mut writer, dc, ac, prediction, block)?;
+                }
+            }
+        }
+        let mut out = writer.finish();
+        out.extend_from_slice(&[0xFF, 0xD9]);
+        Ok(out)
+    }
+
+    /// MCUs per row and column of an interleaved scan.
+    fn mcus(&self) -> (usize, usize) {
+        let h = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let v = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        (
+            (self.width as usize).div_ceil(8 * h),
+            (self.height as usize).div_ceil(8 * v),
+        )
+    }
+
+    /// Blocks per row and column of `component` covering the image.
+    fn component_blocks(&self, component: usize) -> (usize, usize) {
+        let h = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let v = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        let c = &self.components[component];
+        (
+            (self.width as usize * c.h).div_ceil(h).div_ceil(8),
+            (self.height as usize * c.v).div_ceil(v).div_ceil(8),
+        )
+    }
+
+    /// MCUs of an interleaved scan, or of the non-interleaved scan of
+    /// `single`, which has one block per MCU.
+    fn scan_mcus(&self, single: Option<usize>) -> usize {
+        match single {
+            Some(component) => {
+                let (wide, high) = self.component_blocks(component);
+                wide * high
+            }
+            None => {
+                let (wide, high) = self.mcus();
+                wide * high
+            }
+        }
+    }
+
+    /// Blocks of `component` in `mcu`.
+    fn mcu_blocks(&self, component: usize, mcu: usize, single: bool) -> Vec<usize> {
+        let c = &self.components[component];
+        if single {
+            let wide = self.component_blocks(component).0;
+            return vec![mcu / wide * c.wide + mcu % wide];
+        }
+        let mcus_x = self.mcus().0;
+        let (x, y) = (mcu % mcus_x * c.h, mcu / mcus_x * c.v);
+        (0..c.v)
+            .flat_map(|v| (0..c.h).map(move |h| (y + v) * c.wide + x + h))
+            .collect()
+    }
+
+    /// Number of AC coefficients, zero or not.
+    fn len(&self) -> usize {
+        self.components.iter().map(|c| c.blocks.len() * AC).sum()
+    }
+
+    /// Component, block and zigzag index of the AC coefficient at
+    /// `position`, counting block by block.
+    fn locate(&self, mut position: usize) -> (usize, usize, usize) {
+        for (at, component) in self.components.iter().enumerate() {
+            let len = component.blocks.len() * AC;
+            if position < len {
+                return (at, position / AC, 1 + position % AC);
+            }
+            position -= len;
+        }
+        unreachable!("coefficient {position} out of range")
+    }
+
+    fn ac(&self) -> impl Iterator<Item = i16> + '_ {
+        self.components
+            .iter()
+            .flat_map(|c| c.blocks.iter().flat_map(|block| block[1..].iter().copied()))
+    }
+
+    /// Number of non-zero AC coefficients, the most bits they can hold.
+    pub fn coefficients(&self) -> usize {
+        self.ac().filter(|&c| c != 0).count()
+    }
+
+    /// Bytes the coefficients are expected to hold: every coefficient
+    /// of one whose parity is wrong shrinks to zero and holds nothing.
+    pub fn capacity(&self) -> usize {
+        let ones = self.ac().filter(|c| c.abs() == 1).count();
+        (self.coefficients() - ones / 2) / 8
+    }
+
+    /// Hides `bytes` in the coefficients, visited in the order `key`
+    /// gives. Returns the bytes written, fewer when the coefficients run
+    /// out, and the number of coefficients changed.
+    pub fn write(&mut self, bytes: &[u8], key: Option<&str>) -> (usize, usize) {
+        let (total, mut written, mut changed) = (bytes.len() * 8, 0, 0);
+        for position in Order::new(self.len(), key.map(mode::seed)) {
+            if written == total {
+                break;
+            }
+            let (component, block, k) = self.locate(position);
+            let coefficient = &mut self.components[component].blocks[block][k];
+            if *coefficient == 0 {
+                continue;
+            }
+            let bit = bytes[written / 8] >> (written % 8) & 1;
+            if parity(*coefficient) != bit {
+                *coefficient -= coefficient.signum();
+                changed += 1;
+            }
+            if *coefficient != 0 {
+                written += 1;
+            }
+        }
+        (written / 8, changed)
+    }
+
+    /// Reads `len` bytes hidden by [`Jpeg::write`], fewer when the
+    /// coefficients run out.
+    pub fn read(&self, len: usize, key: Option<&str>) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        let mut read = 0;
+        for position in Order::new(self.len(), key.map(mode::seed)) {
+            if read == len * 8 {
+                break;
+            }
+            let (component, block, k) = self.locate(position);
+            let coefficient = self.components[component].blocks[block][k];
+            if coefficient != 0 {
+                bytes[read / 8] |= parity(coefficient) << (read % 8);
+                read += 1;
+            }
+        }
+        bytes.truncate(read / 8);
+        bytes
+    }
+}
+
+/// Bit held by a non-zero coefficient.
+fn parity(coefficient: i16) -> u8 {
+    match coefficient > 0 {
+        true => (coefficient & 1) as u8,
+        false => 1 - (coefficient.unsigned_abs() & 1) as u8,
+    }
+}
+
+/// Segments of a JPEG file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or("truncated JPEG image")?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    /// Next marker, skipping fill bytes.
+    fn marker(&mut self) -> Result<u8> {
+        if self.take(1)? != [0xFF] {
+            return Err("corrupt JPEG image".into());
+        }
+        loop {
+            match self.take(1)?[0] {
+                0xFF => continue,
+                marker => return Ok(marker),
+            }
+        }
+    }
+
+    /// Data of the segment after a marker.
+    fn segment(&mut self) -> Result<&'a [u8]> {
+        let len = u16::from_be_bytes(self.take(2)?.try_into()?) as usize;
+        self.take(len.checked_sub(2).ok_or("corrupt JPEG segment")?)
+    }
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) -> Result<()> {
+    let len = u16::try_from(data.len() + 2).map_err(|_| "JPEG segment too long")?;
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// Canonical Huffman code, as laid out in annex C of the specification.
+#[derive(Debug, Clone)]
+struct Huffman {
+    symbols: Vec<u8>,
+    /// Smallest and largest code of every length, and the index of the
+    /// symbol of the smallest one.
+    first: [i32; 17],
+    last: [i32; 17],
+    index: [usize; 17],
+    /// Code and length of every symbol, length zero when it has none.
+    codes: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8; 16], symbols: &[u8]) -> Result<Huffman> {
+        let total = counts.iter().map(|&count| count as usize).sum::<usize>();
+        if total > 256 || symbols.len() < total {
+            return Err("corrupt JPEG Huffman table".into());
+        }
+        let mut huffman = Huffman {
+            symbols: symbols[..total].to_vec(),
+            first: [0; 17],
+            last: [-1; 17],
+            index: [0; 17],
+            codes: vec![(0, 0); 256],
+        };
+        let (mut code, mut index) = (0, 0);
+        for (len, &count) in (1..=16).zip(counts) {
+            huffman.first[len] = code;
+            huffman.index[len] = index;
+            for &symbol in &symbols[index..index + count as usize] {
+                huffman.codes[symbol as usize] = (code as u16, len as u8);
+                code += 1;
+            }
+            index += count as usize;
+            huffman.last[len] = code - 1;
+            code <<= 1;
+        }
+        Ok(huffman)
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u8> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = code << 1 | bits.read(1) as i32;
+            if code <= self.last[len] && code >= self.first[len] {
+                let at = self.index[len] + (code - self.first[len]) as usize;
+                return Ok(self.symbols[at]);
+            }
+        }
+        Err("corrupt JPEG data".into())
+    }
+}
+
+fn read_huffman(mut segment: &[u8], tables: &mut [[Option<Huffman>; 4]; 2]) -> Result<()> {
+    while let Some((&spec, rest)) = segment.split_first() {
+        let counts: &[u8; 16] = rest
+            .get(..16)
+            .ok_or("corrupt JPEG Huffman table")?
+            .try_into()?;
+        let huffman = Huffman::new(counts, &rest[16..])?;
+        let len = 16 + huffman.symbols.len();
+        tables[(spec >> 4).min(1) as usize][spec as usize & 3] = Some(huffman);
+        segment = &rest[len..];
+    }
+    Ok(())
+}
+
+/// Entropy coded data of a scan with the stuffed zero bytes removed, split
+/// at the restart markers, and the length of the data.
+fn unstuff(data: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut intervals = vec![Vec::new()];
+    let mut at = 0;
+    while at < data.len() {
+        if data[at] != 0xFF {
+            intervals.last_mut().unwrap().push(data[at]);
+            at += 1;
+            continue;
+        }
+        match data.get(at + 1) {
+            Some(0x00) => intervals.last_mut().unwrap().push(0xFF),
+            Some(0xD0..=0xD7) => intervals.push(Vec::new()),
+            // A fill byte in front of a marker.
+            Some(0xFF) => {
+                at += 1;
+                continue;
+            }
+            _ => break,
+        }
+        at += 2;
+    }
+    (intervals, at)
+}
+
+/// Bits of entropy coded data, most significant first, padded with zeros.
+struct Bits<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Bits<'a> {
+    fn new(bytes: &'a [u8]) -> Bits<'a> {
+        Bits { bytes, at: 0 }
+    }
+
+    fn read(&mut self, count: u8) -> u16 {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.bytes.get(self.at / 8).copied().unwrap_or(0);
+            value = value << 1 | (byte >> (7 - self.at % 8) & 1) as u16;
+            self.at += 1;
+        }
+        value
+    }
+
+    /// Coefficient of magnitude category `size`.
+    fn read_value(&mut self, size: u8) -> Result<i32> {
+        if size > 15 {
+            return Err("corrupt JPEG data".into());
+        }
+        let value = self.read(size) as i32;
+        Ok(match size > 0 && value < 1 << (size - 1) {
+            true => value - (1 << size) + 1,
+            false => value,
+        })
+    }
+}
+
+fn decode_block(
+    bits: &mut Bits,
+    dc: &Huffman,
+    ac: &Huffman,
+    prediction: &mut i32,
+    block: &mut [i16; 64],
+) -> Result<()> {
+    let size = dc.decode(bits)?;
+    *prediction += bits.read_value(size)?;
+    block[0] = *prediction as i16;
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(bits)?;
+        let (run, size) = (symbol >> 4, symbol & 15);
+        if size == 0 && run != 15 {
+            break;
+        }
+        k += run as usize;
+        if k >= 64 {
+            return Err("corrupt JPEG data".into());
+        }
+        block[k] = bits.read_value(size)? as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// Entropy coded data with a zero byte stuffed after every `0xFF`.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u16, count: u8) {
+        self.bits = self.bits << count | (value as u32 & ((1 << count) - 1));
+        self.count += count;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.bits >> self.count) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+        }
+        self.bits &= (1 << self.count) - 1;
+    }
+
+    fn code(&mut self, huffman: &Huffman, symbol: u8) -> Result<()> {
+        match huffman.codes[symbol as usize] {
+            (_, 0) => Err("coefficient out of range for the JPEG Huffman tables".into()),
+            (code, len) => {
+                self.write(code, len);
+                Ok(())
+            }
+        }
+    }
+
+    /// Pads the last byte with ones.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.write(0x7F, 8 - self.count);
+        }
+        self.out
+    }
+}
+
+/// Magnitude category of `value` and its bits.
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, bits as u16)
+}
+
+fn encode_block(
+    writer: &mut BitWriter,
+    dc: &Huffman,
+    ac: &Huffman,
+    prediction: &mut i32,
+    block: &[i16; 64],
+) -> Result<()> {
+    let (size, bits) = magnitude(block[0] as i32 - *prediction);
+    *prediction = block[0] as i32;
+    writer.code(dc, size)?;
+    writer.write(bits, size);
+
+    let mut run = 0;
+    for &coefficient in &block[1..] {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            writer.code(ac, 0xF0)?;
+            run -= 16;
+        }
+        let (size, bits) = magnitude(coefficient as i32);
+        writer.code(ac, run << 4 | size)?;
+        writer.write(bits, size);
+        run = 0;
+    }
+    if run > 0 {
+        writer.code(ac, 0x00)?;
+    }
+    Ok(())
+}
+
+// Huffman tables of annex K of the JPEG specification.
+
+const LUMA_DC_COUNTS: [u8; 16] = [
+    0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const LUMA_DC_SYMBOLS: [u8; 12] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+];
+const CHROMA_DC_COUNTS: [u8; 16] = [
+    0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const CHROMA_DC_SYMBOLS: [u8; 12] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+];
+const LUMA_AC_COUNTS: [u8; 16] = [
+    0x00, 0x02, 0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04, 0x00, 0x00, 0x01, 0x7d,
+];
+const LUMA_AC_SYMBOLS: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const CHROMA_AC_COUNTS: [u8; 16] = [
+    0x00, 0x02, 0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04, 0x00, 0x01, 0x02, 0x77,
+];
+const CHROMA_AC_SYMBOLS: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    /// An image of `width` × `height` with one component per sampling
+    /// factor, filled with random coefficients, a fifth of them non-zero.
+    fn random(width: u16, height: u16, sampling: &[(u8, u8)], seed: u64) -> Jpeg {
+        let mut frame = vec![8];
+        frame.extend_from_slice(&height.to_be_bytes());
+        frame.extend_from_slice(&width.to_be_bytes());
+        frame.push(sampling.len() as u8);
+        for (at, &(h, v)) in sampling.iter().enumerate() {
+            frame.extend_from_slice(&[at as u8 + 1, h << 4 | v, (at > 0) as u8]);
+        }
+        let mut jpeg = Jpeg {
+            width: 0,
+            height: 0,
+            tables: [Some([2; 64]), Some([3; 64]), None, None],
+            components: Vec::new(),
+            segments: vec![(0xFE, b"test".to_vec())],
+        };
+        jpeg.read_frame(&frame).unwrap();
+
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        for component in &mut jpeg.components {
+            for block in &mut component.blocks {
+                block[0] = rng.gen_range(-500..500);
+                for coefficient in &mut block[1..] {
+                    if rng.gen_ratio(1, 5) {
+                        *coefficient = rng.gen_range(-300..300);
+                    }
+                }
+            }
+        }
+        jpeg
+    }
+
+    /// [`Jpeg::encode`] with a restart marker every `interval` MCUs.
+    fn encode_with_restarts(jpeg: &Jpeg, interval: u16) -> Vec<u8> {
+        let plain = jpeg.encode().unwrap();
+        let scan = plain.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        let len = u16::from_be_bytes([plain[scan + 2], plain[scan + 3]]) as usize;
+        let mut out = plain[..scan].to_vec();
+        write_segment(&mut out, 0xDD, &interval.to_be_bytes()).unwrap();
+        out.extend_from_slice(&plain[scan..scan + 2 + len]);
+
+        let tables = [
+            (&LUMA_DC_COUNTS, &LUMA_DC_SYMBOLS[..]),
+            (&LUMA_AC_COUNTS, &LUMA_AC_SYMBOLS[..]),
+            (&CHROMA_DC_COUNTS, &CHROMA_DC_SYMBOLS[..]),
+            (&CHROMA_AC_COUNTS, &CHROMA_AC_SYMBOLS[..]),
+        ]
+        .map(|(counts, symbols)| Huffman::new(counts, symbols).unwrap());
+        let single = jpeg.components.len() == 1;
+        let mut predictions = vec![0; jpeg.components.len()];
+        let mut writer = BitWriter {
+            out,
+            bits: 0,
+            count: 0,
+        };
+        for mcu in 0..jpeg.scan_mcus(single.then_some(0)) {
+            if mcu > 0 && mcu % interval as usize == 0 {
+                let mut out = writer.finish();
+                out.extend_from_slice(&[0xFF, 0xD0 + (mcu / interval as usize - 1) as u8 % 8]);
+                writer = BitWriter {
+                    out,
+                    bits: 0,
+                    count: 0,
+                };
+                predictions.fill(0);
+            }
+            for (component, prediction) in predictions.iter_mut().enumerate() {
+                let (dc, ac) = match component {
+                    0 => (&tables[0], &tables[1]),
+                    _ => (&tables[2], &tables[3]),
+                };
+                for block in jpeg.mcu_blocks(component, mcu, single) {
+                    let block = &jpeg.components[component].blocks[block];
+                    encode_block(&mut writer, dc, ac, prediction, block).unwrap();
+                }
+            }
+        }
+        let mut out = writer.finish();
+        out.extend_from_slice(&[0xFF, 0xD9]);
+        out
+    }
+
+    #[test]
+    fn decode_reads_back_encode() {
+        for (sampling, seed) in [(&[(1, 1)][..], 1), (&[(1, 1), (1, 1), (1, 1)][..], 2)] {
+            let jpeg = random(64, 48, sampling, seed);
+            let bytes = jpeg.encode().unwrap();
+            assert_eq!(Jpeg::decode(&bytes).unwrap(), jpeg);
+            assert_eq!(Jpeg::decode(&bytes).unwrap().encode().unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn compressed_image_survives_a_round_trip() {
+        let image = image::RgbImage::from_fn(75, 41, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 6) as u8, (x * y) as u8])
+        });
+        let jpeg = Jpeg::from_image(&DynamicImage::ImageRgb8(image)).unwrap();
+        let bytes = jpeg.encode().unwrap();
+        assert_eq!(Jpeg::decode(&bytes).unwrap(), jpeg);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (75, 41));
+    }
+
+    #[test]
+    fn subsampled_image_with_odd_size() {
+        let jpeg = random(37, 23, &[(2, 2), (1, 1), (1, 1)], 3);
+        // Blocks are padded to whole MCUs of 16 × 16 pixels.
+        assert_eq!(jpeg.components[0].blocks.len(), 6 * 4);
+        assert_eq!(jpeg.components[1].blocks.len(), 3 * 2);
+        let bytes = jpeg.encode().unwrap();
+        assert_eq!(Jpeg::decode(&bytes).unwrap(), jpeg);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (37, 23));
+    }
+
+    #[test]
+    fn single_component_skips_the_padding_blocks() {
+        // A lone component is coded without the blocks padding its MCUs.
+        let jpeg = random(37, 23, &[(2, 2)], 4);
+        let bytes = jpeg.encode().unwrap();
+        let decoded = Jpeg::decode(&bytes).unwrap();
+        assert_eq!(
+            decoded.components[0].blocks[..5],
+            jpeg.components[0].blocks[..5]
+        );
+        assert_eq!(jpeg.scan_mcus(Some(0)), 5 * 3);
+    }
+
+    #[test]
+    fn restart_intervals() {
+        for (sampling, interval) in [(&[(2, 2), (1, 1), (1, 1)][..], 1), (&[(1, 1)][..], 7)] {
+            let jpeg = random(61, 45, sampling, interval as u64);
+            let bytes = encode_with_restarts(&jpeg, interval);
+            assert!(bytes.windows(2).any(|w| w == [0xFF, 0xD1]));
+            let decoded = Jpeg::decode(&bytes).unwrap();
+            assert_eq!(decoded.components, jpeg.components);
+            // The image crate reads the markers to the same pixels.
+            let pixels = image::load_from_memory(&bytes).unwrap();
+            let plain = image::load_from_memory(&jpeg.encode().unwrap()).unwrap();
+            assert_eq!(pixels, plain);
+        }
+    }
+
+    #[test]
+    fn parity_of_negative_coefficients_is_inverted() {
+        assert_eq!([1, 2, 3, -1, -2, -3].map(parity), [1, 0, 1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn embedded_bytes_read_back() {
+        let bytes = (0..200).map(|at| (at * 37 % 256) as u8).collect::<Vec<_>>();
+        for key in [None, Some("key")] {
+            let mut jpeg = random(64, 64, &[(1, 1), (1, 1), (1, 1)], 5);
+            let (written, changed) = jpeg.write(&bytes, key);
+            assert_eq!(written, bytes.len());
+            assert!(changed > 0);
+            assert_eq!(jpeg.read(bytes.len(), key), bytes);
+            let decoded = Jpeg::decode(&jpeg.encode().unwrap()).unwrap();
+            assert_eq!(decoded.read(bytes.len(), key), bytes);
+        }
+    }
+
+    #[test]
+    fn coefficients_shrinking_to_zero_pass_the_bit_on() {
+        let mut jpeg = random(8, 8, &[(1, 1)], 6);
+        let block = &mut jpeg.components[0].blocks[0];
+        block[1..].fill(0);
+        // 1 holds a one and -1 a zero, the other bit shrinks them to zero.
+        block[1..9].copy_from_slice(&[1, -1, 1, -1, 2, -2, 1, -1]);
+        let (written, changed) = jpeg.write(&[0b0000_0010], None);
+        // Two of the ones shrink to zero and pass their bit on, -2 shrinks to
+        // -1 and keeps it, so six bits fit, less than a byte.
+        assert_eq!((written, changed), (0, 3));
+        assert_eq!(
+            jpeg.components[0].blocks[0][1..9],
+            [0, -1, 1, -1, 2, -1, 0, -1]
+        );
+        assert_eq!(jpeg.read(1, None), Vec::<u8>::new());
+
+        let mut jpeg = random(8, 8, &[(1, 1)], 7);
+        let block = &mut jpeg.components[0].blocks[0];
+        block[1..].fill(0);
+        block[1..12].copy_from_slice(&[1, 1, -1, 3, -3, 4, 1, -1, 5, 6, 7]);
+        assert_eq!(jpeg.write(&[0b1010_0110], None), (1, 4));
+        assert_eq!(jpeg.read(1, None), [0b1010_0110]);
+    }
+}
@@ -0,0 +1,526 @@
+//! Hide text in the transparent area of an image.
+//!
+//! By default the payload is written into the colour channels of pixels whose
+//! alpha is zero, so the image looks unchanged while the alpha channel
+//! stays transparent. Other [`Mode`]s hide it in the least significant
+//! bits of every pixel instead, optionally with LSB matching or Hamming
+//! matrix encoding to change fewer samples, in the DCT coefficients of a
+//! JPEG image, or in tiles that survive cropping and rescaling. A PNG
+//! [`Carrier`] chunk can hold the payload instead of the pixels.
+
+pub mod analyze;
+pub mod archive;
+pub mod canvas;
+pub mod carrier;
+pub mod chunk;
+pub mod compress;
+pub mod crypto;
+pub mod ecc;
+pub mod header;
+pub mod jpeg;
+pub mod meta;
+pub mod mode;
+pub mod robust;
+
+pub use archive::Archive;
+pub use canvas::{Canvas, Palette};
+pub use carrier::Carrier;
+pub use compress::Compression;
+pub use crypto::{Identity, Recipient};
+pub use header::Header;
+pub use image;
+pub use meta::Metadata;
+pub use mode::Mode;
+
+use mode::Layout;
+use std::error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+/// Options controlling [`embed`] and [`extract`].
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Write as much of the payload as fits instead of failing.
+    pub truncate: bool,
+    /// Which bits of the image carry the payload on [`embed`].
+    pub mode: Mode,
+    /// Whether [`embed`] writes into the pixels or a PNG chunk.
+    pub carrier: Carrier,
+    /// Key scattering the payload over the image in a pseudo-random order.
+    pub key: Option<String>,
+    /// Algorithm used to compress the text before it is embedded.
+    pub compression: Compression,
+    /// Passphrase used to encrypt on [`embed`] and decrypt on [`extract`].
+    pub passphrase: Option<String>,
+    /// Public keys to encrypt for on [`embed`].
+    pub recipients: Vec<Recipient>,
+    /// Private keys tried to decrypt on [`extract`].
+    pub identities: Vec<Identity>,
+    /// Reed-Solomon check bytes per block of 255 added on [`embed`], no
+    /// error correction when zero.
+    pub ecc: u8,
+}
+
+/// Data hidden in an image together with its [`Metadata`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Payload {
+    pub metadata: Metadata,
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    /// Reads the file at `path` together with its metadata.
+    pub fn from_file(path: &Path) -> Result<Payload> {
+        let data = fs::read(path)?;
+        let metadata = Metadata::for_file(path, &data)?;
+        Ok(Payload { metadata, data })
+    }
+
+    /// Recreates the file in `dir` under its stored name, restoring its
+    /// permissions and modification time where they were recorded. Fails
+    /// rather than overwrite a file of that name.
+    pub fn write_to(&self, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(self.metadata.file_name()?);
+        let file = match File::options().write(true).create_new(true).open(&path) {
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(
+                    format!("{} already exists, not overwriting it", path.display()).into(),
+                );
+            }
+            file => file?,
+        };
+        (&file).write_all(&self.data)?;
+
+        if let Some(time) = self.metadata.modified_time() {
+            file.set_modified(time)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.metadata.mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        Ok(path)
+    }
+}
+
+/// Summary of an [`embed`] call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// Payload bytes written into the image.
+    pub written: usize,
+    /// Bytes the image can hold, including the header.
+    pub capacity: usize,
+    /// Samples, or JPEG coefficients, changed to hold the payload.
+    pub changed: usize,
+}
+
+impl Report {
+    /// Samples changed per embedded bit, header included.
+    pub fn change_rate(&self) -> f64 {
+        self.changed as f64 / ((self.written + Header::SIZE) * 8) as f64
+    }
+}
+
+/// Hides `text` in `image` using [`Options::mode`].
+pub fn embed(image: &mut Canvas, text: &[u8], options: &Options) -> Result<Report> {
+    let payload = Payload {
+        metadata: Metadata::default(),
+        data: text.to_vec(),
+    };
+    embed_payload(image, &payload, options)
+}
+
+/// Hides `payload` and its metadata in `image`.
+pub fn embed_payload(image: &mut Canvas, payload: &Payload, options: &Options) -> Result<Report> {
+    let mut flags = 0;
+    let mut text = Vec::new();
+
+    if !payload.metadata.is_empty() {
+        text = payload.metadata.to_bytes()?;
+        flags |= header::FLAG_METADATA;
+    }
+    text.extend_from_slice(&payload.data);
+
+    embed_bytes(image, &text, flags, options)
+}
+
+/// Hides all files of `archive` in `image`.
+pub fn embed_archive(image: &mut Canvas, archive: &Archive, options: &Options) -> Result<Report> {
+    embed_bytes(image, &archive.to_bytes()?, header::FLAG_ARCHIVE, options)
+}
+
+fn embed_bytes(image: &mut Canvas, text: &[u8], flags: u8, options: &Options) -> Result<Report> {
+    if options.carrier != Carrier::Pixels {
+        let bytes = encode_payload(text, flags, options)?;
+        carrier::write(image, options.carrier, &bytes)?;
+        return Ok(Report {
+            written: bytes.len() - Header::SIZE,
+            capacity: carrier::MAX_LEN,
+            changed: 0,
+        });
+    }
+
+    options.mode.validate()?;
+    if options.mode.needs_alpha() && !image.has_alpha() {
+        return Err(format!(
+            "the {} mode needs an image with alpha channel",
+            options.mode
+        )
+        .into());
+    }
+    match (options.mode, image.palette()) {
+        (Mode::Palette, None) => return Err("the palette mode needs an indexed image".into()),
+        (Mode::Palette, Some(_)) => image.pair_palette(),
+        (_, Some(_)) => return Err("indexed images only support the palette mode".into()),
+        _ => {}
+    }
+    let bytes = encode_payload(text, flags, options)?;
+    carrier::remove(image);
+    let (written, capacity, changed) = match options.mode {
+        Mode::Jpeg => {
+            let jpeg = image.compress_jpeg()?;
+            let (written, changed) = jpeg.write(&bytes, options.key.as_deref());
+            (written, jpeg.capacity(), changed)
+        }
+        Mode::Robust => {
+            let (written, changed) = robust::write(image, &bytes, options.key.as_deref())?;
+            (written, robust::CAPACITY, changed)
+        }
+        mode => {
+            if let Mode::Cover {
+                neighbour: true, ..
+            } = mode
+            {
+                image.recolour_transparent();
+            }
+            let layout = Layout::new(image, mode, options.key.as_deref());
+            let (written, changed) = layout.write(image.samples_mut(), &bytes);
+            (written, layout.capacity(), changed)
+        }
+    };
+
+    if written < bytes.len() && !options.truncate {
+        return Err("there is not enough free space in the image".into());
+    }
+
+    Ok(Report {
+        written: written.saturating_sub(Header::SIZE),
+        capacity,
+        changed,
+    })
+}
+
+/// Reads the text hidden in `image` by [`embed`].
+pub fn extract(image: &Canvas, options: &Options) -> Result<Vec<u8>> {
+    Ok(extract_payload(image, options)?.0.data)
+}
+
+/// Reads the data hidden in `image` together with its metadata, and the
+/// number of damaged bytes the error correction repaired, `None` when the
+/// payload has no error correction.
+pub fn extract_payload(image: &Canvas, options: &Options) -> Result<(Payload, Option<usize>)> {
+    let (header, text, repaired) = extract_bytes(image, options)?;
+    if header.has(header::FLAG_ARCHIVE) {
+        return Err("the image holds an archive of several files".into());
+    }
+
+    Ok((decode_metadata(&header, text)?, repaired))
+}
+
+fn decode_metadata(header: &Header, text: Vec<u8>) -> Result<Payload> {
+    let mut payload = Payload {
+        metadata: Metadata::default(),
+        data: text,
+    };
+    if header.has(header::FLAG_METADATA) {
+        let len;
+        (payload.metadata, len) = Metadata::from_bytes(&payload.data)?;
+        payload.data.drain(..len);
+
+        if payload
+            .metadata
+            .size
+            .is_some_and(|size| size != payload.data.len() as u64)
+        {
+            return Err("hidden file is truncated".into());
+        }
+    }
+
+    Ok(payload)
+}
+
+/// Reads the files hidden in `image`, and the number of damaged bytes
+/// repaired like [`extract_payload`]; a single hidden file is returned as
+/// an archive with one entry.
+pub fn extract_archive(image: &Canvas, options: &Options) -> Result<(Archive, Option<usize>)> {
+    let (header, text, repaired) = extract_bytes(image, options)?;
+    if header.has(header::FLAG_ARCHIVE) {
+        return Ok((Archive::from_bytes(&text)?, repaired));
+    }
+
+    let archive = Archive {
+        entries: vec![decode_metadata(&header, text)?],
+    };
+    Ok((archive, repaired))
+}
+
+fn extract_bytes(image: &Canvas, options: &Options) -> Result<(Header, Vec<u8>, Option<usize>)> {
+    let (header, body) = read_payload(image, options)?;
+    let (text, repaired) = decode_payload(&header, body, options)?;
+    Ok((header, text, repaired))
+}
+
+fn encode_payload(text: &[u8], flags: u8, options: &Options) -> Result<Vec<u8>> {
+    let mut header = Header::new(0);
+    header.flags |= flags;
+    header.mode = options.mode;
+
+    let mut body = options.compression.compress(text)?;
+    header.flags |= options.compression.flags();
+
+    if let Some(passphrase) = &options.passphrase {
+        if !options.recipients.is_empty() {
+            return Err("use either a passphrase or recipients, not both".into());
+        }
+        body = crypto::seal(&body, passphrase)?;
+        header.flags |= header::FLAG_ENCRYPTED;
+    } else if !options.recipients.is_empty() {
+        body = crypto::seal_for(&body, &options.recipients)?;
+        header.flags |= header::FLAG_RECIPIENTS;
+    }
+
+    let mut checked = crc32fast::hash(&body).to_le_bytes().to_vec();
+    checked.append(&mut body);
+    body = checked;
+    header.flags |= header::FLAG_CHECKSUM;
+
+    header.len = body.len() as u64;
+    if options.ecc > 0 {
+        body = ecc::encode(&body, options.ecc)?;
+        header.ecc = options.ecc;
+        header.len = (header::CHECK_SIZE + body.len()) as u64;
+        body.splice(..0, header.check_bytes()?);
+    }
+    let mut bytes = header.to_bytes()?.to_vec();
+    bytes.append(&mut body);
+    Ok(bytes)
+}
+
+/// Strips the check bytes of a `body` read behind `header`, repairing it
+/// first, and returns it with the number of bytes repaired, `None` when
+/// the payload has no error correction.
+fn repair_body(header: &Header, body: Vec<u8>) -> Result<(Vec<u8>, Option<usize>)> {
+    if header.ecc == 0 {
+        return Ok((body, None));
+    }
+    let (body, repaired) = ecc::decode(&body[header::CHECK_SIZE.min(body.len())..], header.ecc)?;
+    Ok((body, Some(repaired)))
+}
+
+/// Repairs, checks, decrypts and decompresses a `body` read behind
+/// `header`, returning it with the number of bytes repaired.
+fn decode_payload(
+    header: &Header,
+    body: Vec<u8>,
+    options: &Options,
+) -> Result<(Vec<u8>, Option<usize>)> {
+    let (mut text, repaired) = repair_body(header, body)?;
+
+    if header.has(header::FLAG_CHECKSUM) {
+        if text.len() < 4 || crc32fast::hash(&text[4..]).to_le_bytes() != text[..4] {
+            return Err("payload corrupted: checksum mismatch".into());
+        }
+        text.drain(..4);
+    }
+
+    if header.has(header::FLAG_ENCRYPTED) {
+        let Some(passphrase) = &options.passphrase else {
+            return Err("hidden text is encrypted, a passphrase is required".into());
+        };
+        text = crypto::open(&text, passphrase)?;
+    } else if header.has(header::FLAG_RECIPIENTS) {
+        if options.identities.is_empty() {
+            return Err("hidden text is encrypted for recipients, an identity is required".into());
+        }
+        text = crypto::open_with(&text, &options.identities)?;
+    }
+
+    let text = Compression::from_flags(header.flags)?.decompress(&text)?;
+    Ok((text, repaired))
+}
+
+/// Writes `bytes` into the samples of `canvas` used by [`Options::mode`] in
+/// the order given by [`Options::key`], returning the number of bytes
+/// written.
+pub fn encode_data(canvas: &mut Canvas, bytes: &[u8], options: &Options) -> usize {
+    Layout::new(canvas, options.mode, options.key.as_deref())
+        .write(canvas.samples_mut(), bytes)
+        .0
+}
+
+/// Builds the [`Header`] written in front of the text.
+pub fn encode_text_len(text: &[u8]) -> Result<Vec<u8>> {
+    Ok(Header::new(text.len() as u64).to_bytes()?.to_vec())
+}
+
+/// Bytes read to find a header, including the check bytes protecting it.
+const PROBE_SIZE: usize = Header::SIZE + header::CHECK_SIZE;
+
+/// Looks for a payload in the [`Carrier`] chunks of `canvas`, then in its
+/// pixels or JPEG coefficients, trying every [`Mode`] the image supports
+/// with [`Options::key`]. The slow search for [`Mode::Robust`] tiles only
+/// runs when [`Options::mode`] asks for it.
+///
+/// Returns `None` when no mode finds a [`Header`], and an error when the
+/// header declares more bytes than the image can hold.
+pub fn detect(canvas: &Canvas, options: &Options) -> Result<Option<Header>> {
+    Ok(find_header(canvas, options)?.map(|(header, _)| header))
+}
+
+/// [`detect`], also returning the whole payload of a [`Carrier`] chunk or
+/// of [`Mode::Robust`], which are read at once.
+fn find_header(canvas: &Canvas, options: &Options) -> Result<Option<(Header, Vec<u8>)>> {
+    if let Some((_, bytes)) = carrier::find(canvas) {
+        return Ok(Some((Header::from_bytes(&Header::repair(&bytes))?, bytes)));
+    }
+
+    let modes = Mode::candidates().into_iter().filter(|&mode| {
+        (canvas.has_alpha() || !mode.needs_alpha())
+            && (mode == Mode::Palette) == canvas.palette().is_some()
+            && (mode != Mode::Jpeg || canvas.jpeg().is_some())
+            && (mode != Mode::Robust || options.mode == Mode::Robust)
+    });
+    for mode in modes {
+        let key = options.key.as_deref();
+        let (bytes, capacity) = match mode {
+            Mode::Jpeg => match canvas.jpeg() {
+                Some(jpeg) => (jpeg.read(PROBE_SIZE, key), jpeg.coefficients() / 8),
+                None => continue,
+            },
+            Mode::Robust => (robust::read(canvas, key), robust::CAPACITY),
+            mode => {
+                let layout = Layout::new(canvas, mode, key);
+                (
+                    layout.read(canvas.samples(), 0, PROBE_SIZE),
+                    layout.capacity(),
+                )
+            }
+        };
+
+        let repaired = Header::repair(&bytes);
+        if !Header::is_present(&repaired) {
+            continue;
+        }
+
+        let header = Header::from_bytes(&repaired)?;
+        if header.mode != mode {
+            continue;
+        }
+
+        let available = capacity - Header::SIZE;
+        if header.len > available as u64 {
+            return Err(format!(
+                "hidden text declares {} bytes but the image holds at most {available}",
+                header.len
+            )
+            .into());
+        }
+
+        let bytes = match mode {
+            Mode::Robust => bytes,
+            _ => Vec::new(),
+        };
+        return Ok(Some((header, bytes)));
+    }
+
+    Ok(None)
+}
+
+fn read_payload(canvas: &Canvas, options: &Options) -> Result<(Header, Vec<u8>)> {
+    let Some((header, bytes)) = find_header(canvas, options)? else {
+        return Err("no hidden text found in the image".into());
+    };
+    let body = read_body(canvas, &header, bytes, options)?;
+    Ok((header, body))
+}
+
+/// Reads the body behind `header`, out of the `bytes` [`find_header`]
+/// returned with it when it read them all.
+fn read_body(
+    canvas: &Canvas,
+    header: &Header,
+    bytes: Vec<u8>,
+    options: &Options,
+) -> Result<Vec<u8>> {
+    let key = options.key.as_deref();
+    let end = Header::SIZE + header.len as usize;
+    if !bytes.is_empty() {
+        let body = bytes
+            .get(Header::SIZE..end)
+            .ok_or("hidden text is truncated")?;
+        return Ok(body.to_vec());
+    }
+
+    Ok(match canvas.jpeg().filter(|_| header.mode == Mode::Jpeg) {
+        Some(jpeg) => jpeg.read(end, key)[Header::SIZE..].to_vec(),
+        None => Layout::new(canvas, header.mode, key).read(
+            canvas.samples(),
+            Header::SIZE,
+            header.len as usize,
+        ),
+    })
+}
+
+/// Reads the bytes written by [`encode_data`] behind their [`Header`],
+/// without decrypting them.
+pub fn decode_text(canvas: &Canvas, options: &Options) -> Result<Vec<u8>> {
+    Ok(read_payload(canvas, options)?.1)
+}
+
+/// Like [`decode_text`], with the damage the error correction repairs
+/// undone and its check bytes stripped, so copies that lost a few bytes
+/// to JPEG compression read the same.
+pub fn decode_repaired_text(canvas: &Canvas, options: &Options) -> Result<Vec<u8>> {
+    let (header, body) = read_payload(canvas, options)?;
+    Ok(repair_body(&header, body)?.0)
+}
+
+/// [`detect`], also returning the number of damaged payload bytes the
+/// error correction repairs on extraction, `None` when the payload has no
+/// error correction, or the error keeping it from repairing them.
+pub fn detect_repairs(
+    canvas: &Canvas,
+    options: &Options,
+) -> Result<Option<(Header, Result<Option<usize>>)>> {
+    let Some((header, bytes)) = find_header(canvas, options)? else {
+        return Ok(None);
+    };
+    let repaired = read_body(canvas, &header, bytes, options)
+        .and_then(|body| Ok(repair_body(&header, body)?.1));
+    Ok(Some((header, repaired)))
+}
+
+/// Number of bytes the samples of `canvas` used by `mode` can hold, after
+/// pairing the palette for [`Mode::Palette`] and compressing images that
+/// are not JPEGs for [`Mode::Jpeg`]. [`Mode::Robust`] holds the same
+/// bytes in every image of at least one tile.
+pub fn available_bytes(canvas: &Canvas, mode: Mode) -> usize {
+    if mode == Mode::Robust {
+        let fits = canvas.width().min(canvas.height()) as usize >= robust::TILE;
+        return if fits { robust::CAPACITY } else { 0 };
+    }
+    if mode == Mode::Jpeg {
+        let mut compressed = canvas.clone();
+        return compressed.compress_jpeg().map_or(0, |jpeg| jpeg.capacity());
+    }
+    if mode == Mode::Palette && canvas.palette().is_some() {
+        let mut paired = canvas.clone();
+        paired.pair_palette();
+        return Layout::new(&paired, mode, None).capacity();
+    }
+    Layout::new(canvas, mode, None).capacity()
+}
@@ -1,17 +1,29 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
 use clap::{crate_version, value_parser, Arg, ArgMatches, Command, ValueHint};
-use image::{open, save_buffer, ColorType, ImageFormat, ImageResult};
-use std::error;
-use std::fs::{self, write};
-use std::path::PathBuf;
-
-type Size = (u32, u32);
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+use image::{ColorType, ImageFormat};
+use std::fs::{self, write, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use subtxt::analyze::analyze;
+use subtxt::crypto::parse_keys;
+use subtxt::header::{CHECK_SIZE, FLAG_ENCRYPTED, FLAG_RECIPIENTS};
+use subtxt::{
+    available_bytes, carrier, decode_repaired_text, detect, detect_repairs, ecc, embed_archive,
+    embed_payload, extract_archive, extract_payload, robust, Archive, Canvas, Carrier, Compression,
+    Header, Identity, Mode, Options, Payload, Report, Result,
+};
 
 #[derive(Default)]
 struct TxtInImg {
-    data: Vec<u8>,
-    size: Size,
-    rgba: Option<ColorType>,
+    image: Canvas,
+    /// PNG colour type and bit depth of the input image.
+    source: Option<(png::ColorType, png::BitDepth)>,
+    options: Options,
+    payload: Option<Payload>,
+    /// Bytes behind the header just hidden, repaired by the error
+    /// correction, which the written image must read back. `None` when
+    /// they do not read back from the image itself.
+    embedded: Option<Vec<u8>>,
 }
 
 impl TxtInImg {
@@ -19,181 +31,762 @@ impl TxtInImg {
         TxtInImg::default()
     }
 
-    fn open_image(&mut self, app: &ArgMatches) -> ImageResult<()> {
-        if let Some(path) = app
-            .get_one::<PathBuf>("input_image")
-        {
-            let image = open(path)?;
-            self.rgba = match image.color() {
-                ColorType::Rgba8 => Some(ColorType::Rgba8),
-                _ => None,
+    fn open_image(&mut self, app: &ArgMatches) -> Result<()> {
+        if let Some(path) = app.get_one::<PathBuf>("input_image") {
+            self.image = Canvas::open(path)?;
+            self.source = png_color(path);
+        }
+
+        Ok(())
+    }
+
+    fn save_data(&mut self, app: &ArgMatches) -> Result<()> {
+        if let Some(path) = app.get_one::<PathBuf>("input_text") {
+            self.fit_output(app.get_one::<PathBuf>("output").map(PathBuf::as_path))?;
+            self.check_color_model()?;
+            self.options.truncate = !app.get_flag("ignore");
+            let payload = Payload::from_file(path)?;
+            let report = embed_payload(&mut self.image, &payload, &self.options)?;
+            print_report(&report, &self.options);
+            self.embedded = decode_repaired_text(&self.image, &self.options).ok();
+        }
+        Ok(())
+    }
+
+    fn strip_metadata(&mut self, app: &ArgMatches) {
+        if app.get_flag("strip_metadata") {
+            self.image.strip_metadata();
+        }
+    }
+
+    fn save_img(&self, app: &ArgMatches) -> Result<()> {
+        if let Some(path) = app.get_one::<PathBuf>("output") {
+            let verify = !app.get_flag("no_verify");
+            self.write_img(path, app.contains_id("input_text"), verify)?;
+        }
+        Ok(())
+    }
+
+    /// Converts the image to a colour type the embedding mode and the
+    /// format of `path` can store before a payload is hidden in it.
+    fn fit_output(&mut self, path: Option<&Path>) -> Result<()> {
+        let format = path.map(ImageFormat::from_path).transpose()?;
+        if self.options.carrier != Carrier::Pixels {
+            return match format {
+                Some(ImageFormat::Png) | None => Ok(()),
+                Some(_) => {
+                    Err(format!("the {} carrier needs a PNG output", self.options.carrier).into())
+                }
             };
-            self.size = (image.width(), image.height());
-            self.data = image.into_rgba8().into_vec();
+        }
+        if self.options.mode == Mode::Palette
+            && format.is_some_and(|format| format != ImageFormat::Png)
+        {
+            return Err("the palette mode can only write indexed PNG images".into());
+        }
+        if self.options.mode == Mode::Jpeg
+            && format.is_some_and(|format| format != ImageFormat::Jpeg)
+        {
+            return Err("the jpeg mode can only write JPEG images".into());
+        }
+        // Explain a lossy output before converting the image for it.
+        if let Some(format) = format.filter(|&format| {
+            self.options.mode != Mode::Jpeg && !keeps_pixels(format, self.options.mode)
+        }) {
+            return Err(lossy_output(format).into());
+        }
+        if self.image.palette().is_some() && self.options.mode != Mode::Palette {
+            eprintln!(
+                "warning: converting the indexed image to true colour, \
+                 only the palette mode in a PNG keeps it indexed"
+            );
+            self.image = std::mem::take(&mut self.image).into_true_colour();
         }
 
+        let Some(format) = format else {
+            return Ok(());
+        };
+        let color = output_color(format, self.image.color());
+        if color != self.image.color() {
+            eprintln!(
+                "warning: converting the image from {:?} to {color:?}, {} cannot store {:?}",
+                self.image.color(),
+                format_name(format),
+                self.image.color()
+            );
+            self.image = std::mem::take(&mut self.image).into_color(color);
+        }
         Ok(())
     }
 
-    fn encode_data(&mut self, app: &ArgMatches, sub_vec: Vec<u8>) -> Result<()> {
-        let mut sub_iter = sub_vec.iter();
-        let iter = self.data.chunks_mut(4).filter(|chunk| chunk[3] == 0);
+    fn write_img(&self, path: &Path, embedded: bool, verify: bool) -> Result<()> {
+        let format = ImageFormat::from_path(path)?;
+        self.check_keeps_payload(format, embedded)?;
 
-        'outer: for chunk in iter {
-            for elem in chunk.iter_mut().take(3) {
-                let Some(sub_elem) = sub_iter.next() else {
-                    break 'outer;
-                };
-                *elem = *sub_elem;
+        let mut image = self.image.clone();
+        if format != ImageFormat::Png {
+            if !image.chunks().is_empty() {
+                eprintln!(
+                    "warning: dropping the metadata of the input image, {} cannot store PNG chunks",
+                    format_name(format)
+                );
+            }
+            image = image.into_true_colour();
+        }
+        let color = output_color(format, image.color());
+        if color != image.color() {
+            if embedded {
+                return Err(format!(
+                    "{} cannot store {:?} without destroying the hidden text",
+                    format_name(format),
+                    image.color()
+                )
+                .into());
+            }
+            eprintln!(
+                "warning: writing {color:?} instead of {:?}, {} cannot store it",
+                image.color(),
+                format_name(format)
+            );
+            image = image.into_color(color);
+        }
+        if let Some((source, depth)) = self.source {
+            let indexed = source == png::ColorType::Indexed;
+            if image.palette().is_none() && (indexed || (depth as u8) < 8) {
+                eprintln!(
+                    "warning: writing {color:?} instead of the {}-bit {source:?} colour of the input image{}",
+                    depth as u8,
+                    if embedded { ", the hidden text needs every sample" } else { "" }
+                );
             }
         }
 
-        if app.get_flag("ignore") && sub_iter.next().is_some() {
-            return Err("there is not enough free space in the image".into());
+        if !(embedded && verify) {
+            return image.save(path, format);
         }
 
+        // Write next to the output and only replace it once the text reads
+        // back, so a broken image never overwrites the input.
+        let name = path.file_name().ok_or("the output path has no file name")?;
+        let temp = path.with_file_name(format!(".{}", name.to_string_lossy()));
+        image.save(&temp, format)?;
+        if let Err(error) = self.verify(&temp, format) {
+            fs::remove_file(&temp)?;
+            return Err(error);
+        }
+        fs::rename(&temp, path)?;
         Ok(())
     }
 
-    fn write_data(&mut self, app: &ArgMatches, path: &PathBuf) -> Result<()> {
-        let mut text_bytes = open_text_file(path)?;
-        let mut bytes = encode_text_len(&text_bytes);
-        bytes.append(&mut text_bytes);
-        self.encode_data(app, bytes)?;
+    /// Reopens the image written to `path` like the input image and checks
+    /// that the hidden text reads back as it was embedded.
+    fn verify(&self, path: &Path, format: ImageFormat) -> Result<()> {
+        let written = Canvas::open(path)?;
+        let read = decode_repaired_text(&written, &self.options).ok();
+        if read.is_none() || read != self.embedded {
+            return Err(format!(
+                "the hidden text does not read back from the {} image, it was not saved",
+                format_name(format)
+            )
+            .into());
+        }
         Ok(())
     }
 
-    fn save_data(&mut self, app: &ArgMatches) -> Result<()> {
-        if let Some(path) = app
-            .get_one::<PathBuf>("input_text")
-        {
-            let Some(_) = self.rgba else {
-                return Err("unsupported color model".into());
-            };
-            self.write_data(app, path)?;
+    fn read_options(&mut self, app: &ArgMatches, embedding: bool, extracting: bool) -> Result<()> {
+        if let Some(compression) = app.get_one::<String>("compress") {
+            self.options.compression = compression.parse()?;
+        }
+        self.options.key = app.get_one::<String>("key").cloned();
+        if let Some(mode) = app.get_one::<String>("mode") {
+            self.options.mode = mode.parse()?;
+        }
+        if let Some(carrier) = app.get_one::<String>("carrier") {
+            self.options.carrier = carrier.parse()?;
+        }
+        if let Some(&ecc) = app.get_one::<u8>("ecc") {
+            self.options.ecc = ecc;
+        }
+        let code_bits = app.get_one::<u8>("bits").copied();
+        match &mut self.options.mode {
+            Mode::Transparent | Mode::Palette | Mode::Jpeg => {}
+            // Tiles read back from a resized copy lose bits, repair them
+            // unless asked otherwise.
+            Mode::Robust if self.options.ecc == 0 => self.options.ecc = robust::ECC,
+            Mode::Robust => {}
+            Mode::Lsb { bits, alpha } => {
+                *bits = code_bits.unwrap_or(1);
+                *alpha = app.get_flag("alpha");
+            }
+            Mode::Matching { alpha } => *alpha = app.get_flag("alpha"),
+            Mode::Hamming { bits, alpha } => {
+                *bits = code_bits.unwrap_or(*bits);
+                *alpha = app.get_flag("alpha");
+            }
+            Mode::Cover { bits, neighbour } => {
+                *bits = code_bits.unwrap_or(1);
+                *neighbour = app.get_flag("neighbour");
+            }
+        }
+        self.options.passphrase = app.get_one::<String>("passphrase").cloned();
+
+        for recipient in app.get_many::<String>("recipient").into_iter().flatten() {
+            if recipient.starts_with("age1") {
+                self.options.recipients.push(recipient.parse()?);
+            } else {
+                let mut keys = parse_keys(&fs::read_to_string(recipient)?)?;
+                self.options.recipients.append(&mut keys);
+            }
+        }
+
+        for path in app.get_many::<PathBuf>("identity").into_iter().flatten() {
+            let mut keys = parse_keys(&fs::read_to_string(path)?)?;
+            self.options.identities.append(&mut keys);
+        }
+
+        if self.options.passphrase.is_some() {
+            return Ok(());
+        }
+
+        if embedding && app.get_flag("encrypt") {
+            self.options.passphrase = Some(env_passphrase().map_or_else(new_passphrase, Ok)?);
+        } else if extracting {
+            if let Some(header) = detect(&self.image, &self.options)? {
+                if header.has(FLAG_ENCRYPTED) {
+                    let passphrase =
+                        env_passphrase().map_or_else(|| prompt_passphrase("passphrase: "), Ok)?;
+                    self.options.passphrase = Some(passphrase);
+                }
+            }
         }
+
         Ok(())
     }
 
-    fn save_img(&self, app: &ArgMatches) -> Result<()> {
-        if let Some(path) = app.get_one::<PathBuf>("output") {
-            let color_type = ColorType::Rgba8;
-            let format = ImageFormat::from_path(path)?;
+    fn hidden_payload(&mut self) -> Result<&Payload> {
+        if self.payload.is_none() {
+            let (payload, repaired) = extract_payload(&self.image, &self.options)?;
+            print_corrections(repaired);
+            self.payload = Some(payload);
+        }
 
-            if app.contains_id("input_text") {
-                let (ImageFormat::Png | ImageFormat::Tiff) = format else {
-                    return Err("unsupported image output format".into());
-                };
-            }
+        Ok(self.payload.as_ref().unwrap())
+    }
 
-            save_buffer(path, &self.data, self.size.0, self.size.1, color_type)?;
+    fn save_invisible_text(&mut self, app: &ArgMatches) -> Result<()> {
+        if let Some(path) = app.get_one::<PathBuf>("output_text") {
+            let payload = self.hidden_payload()?;
+
+            if path.is_dir() {
+                payload.write_to(path)?;
+            } else {
+                write(path, &payload.data)?;
+            }
         }
         Ok(())
     }
 
-    fn decode_text_len(&self) -> Option<usize> {
-        if self.data.len() <= 12 {
-            return None;
-        };
+    fn extract_file(&mut self, app: &ArgMatches) -> Result<()> {
+        if let Some(dir) = app.get_one::<PathBuf>("extract_to") {
+            let payload = self.hidden_payload()?;
 
-        let mut len = Vec::from(&self.data[..10]);
-        len.remove(9);
-        len.remove(4);
+            fs::create_dir_all(dir)?;
+            let path = payload.write_to(dir)?;
+            println!("\nextracted {}\n", path.display());
+        }
+        Ok(())
+    }
 
-        Some(usize::from_ne_bytes(len.try_into().unwrap()))
+    fn check_color_model(&self) -> Result<()> {
+        if self.options.carrier == Carrier::Pixels
+            && self.options.mode.needs_alpha()
+            && !self.image.has_alpha()
+        {
+            return Err("unsupported color model".into());
+        }
+        Ok(())
     }
 
-    fn decode_text(&mut self) -> Option<Vec<u8>> {
-        let Some(len) = self.decode_text_len() else {
-            return None;
-        };
+    fn add_files(&mut self, app: &ArgMatches) -> Result<()> {
+        self.strip_metadata(app);
+        let mut archive = Archive::default();
+        if let Some(header) = detect(&self.image, &self.options)? {
+            // The recipients are not recorded, so they cannot be kept.
+            if header.has(FLAG_RECIPIENTS) && self.options.recipients.is_empty() {
+                return Err(
+                    "the archive is sealed for recipients, re-sealing needs every recipient, pass -r"
+                        .into(),
+                );
+            }
+            if !app.contains_id("compress") {
+                self.options.compression = Compression::from_flags(header.flags)?;
+            }
+            if !app.contains_id("mode") {
+                self.options.mode = header.mode;
+            }
+            if !app.contains_id("ecc") {
+                self.options.ecc = header.ecc;
+            }
+            if !app.contains_id("carrier") {
+                if let Some((carrier, _)) = carrier::find(&self.image) {
+                    self.options.carrier = carrier;
+                }
+            }
+            archive = extract_archive(&self.image, &self.options)?.0;
+        }
+        let path = app
+            .get_one::<PathBuf>("output")
+            .or(app.get_one::<PathBuf>("input_image"))
+            .unwrap();
+        self.fit_output(Some(path))?;
+        self.check_color_model()?;
 
-        let sub_vec = self
-            .data
-            .chunks_mut(4)
-            .filter(|chunk| chunk[3] == 0)
-            .flat_map(|chunk| [chunk[0], chunk[1], chunk[2]])
-            .skip(12)
-            .take(len)
-            .collect::<Vec<_>>();
+        for path in app.get_many::<PathBuf>("files").into_iter().flatten() {
+            archive.insert(Payload::from_file(path)?);
+        }
+        let report = embed_archive(&mut self.image, &archive, &self.options)?;
+        print_report(&report, &self.options);
+        self.embedded = decode_repaired_text(&self.image, &self.options).ok();
+
+        self.write_img(path, true, !app.get_flag("no_verify"))
+    }
+
+    fn list_files(&self) -> Result<()> {
+        let (archive, repaired) = extract_archive(&self.image, &self.options)?;
+        print_corrections(repaired);
 
-        if sub_vec.len() != len {
-            return None;
+        println!();
+        for entry in &archive.entries {
+            println!(
+                "{:>12}  {}",
+                entry.data.len(),
+                entry.metadata.name.as_deref().unwrap_or("-")
+            );
         }
-        Some(sub_vec)
+        println!();
+
+        Ok(())
     }
 
-    fn save_invisible_text(&mut self, app: &ArgMatches) -> Result<()> {
-        if let Some(path) = app.get_one::<PathBuf>("output_text") {
-            let Some(vec) = self.decode_text() else {
-                return Err("error extracting text".into());
-            };
+    fn extract_files(&self, app: &ArgMatches) -> Result<()> {
+        let (archive, repaired) = extract_archive(&self.image, &self.options)?;
+        print_corrections(repaired);
+        let dir = app.get_one::<PathBuf>("extract_to").unwrap();
 
-            write(path, String::from_utf8(vec.to_vec()).unwrap())?;
+        let entries = match app.get_one::<String>("name") {
+            Some(name) => {
+                let Some(entry) = archive.get(name) else {
+                    return Err(format!("no file named {name:?} in the image").into());
+                };
+                vec![entry]
+            }
+            None => archive.entries.iter().collect(),
+        };
+
+        fs::create_dir_all(dir)?;
+        println!();
+        for entry in entries {
+            println!("extracted {}", entry.write_to(dir)?.display());
         }
+        println!();
+
         Ok(())
     }
 
     fn print_invisible_text(&mut self, app: &ArgMatches) -> Result<()> {
         if app.get_flag("print") {
-            let Some(vec) = self.decode_text() else {
-                return Err("error extracting text".into());
-            };
+            let format = app.get_one::<String>("print_format").unwrap().clone();
+            let payload = self.hidden_payload()?;
 
-            println!("{}\n", String::from_utf8(vec.to_vec()).unwrap());
+            match format.as_str() {
+                "hex" => {
+                    let hex = payload
+                        .data
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect::<String>();
+                    println!("{hex}\n");
+                }
+                "base64" => println!("{}\n", BASE64_STANDARD.encode(&payload.data)),
+                _ => {
+                    let text = std::str::from_utf8(&payload.data)
+                        .ok()
+                        .filter(|_| payload.metadata.is_text());
+                    let Some(text) = text else {
+                        return Err("the hidden data is binary, save it with -O or print it \
+                             with --print-format hex or base64"
+                            .into());
+                    };
+                    println!("{text}\n");
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn print_available_bytes(&self, app: &ArgMatches) {
+    /// Fails when writing the image as `format` loses the text just
+    /// embedded, or the text the input image already carries.
+    fn check_keeps_payload(&self, format: ImageFormat, embedded: bool) -> Result<()> {
+        let hidden = match carrier::find(&self.image) {
+            Some((carrier, _)) => Some((carrier, self.options.mode)),
+            None if embedded => Some((Carrier::Pixels, self.options.mode)),
+            None => detect(&self.image, &self.options)
+                .ok()
+                .flatten()
+                .map(|header| (Carrier::Pixels, header.mode)),
+        };
+        let name = format_name(format);
+        match hidden {
+            None => Ok(()),
+            Some((Carrier::Pixels, Mode::Jpeg)) => match format {
+                ImageFormat::Jpeg if self.image.jpeg().is_some() => Ok(()),
+                _ => Err(format!(
+                    "the hidden text is in the JPEG coefficients of the image, \
+                     which {name} cannot store"
+                )
+                .into()),
+            },
+            Some((Carrier::Pixels, mode)) if keeps_pixels(format, mode) => Ok(()),
+            Some((Carrier::Pixels, _)) => Err(lossy_output(format).into()),
+            Some(_) if format == ImageFormat::Png => Ok(()),
+            Some((carrier, _)) => {
+                Err(format!("the hidden text is in a {carrier}, which {name} cannot store").into())
+            }
+        }
+    }
+
+    fn print_available_bytes(&self, app: &ArgMatches) -> Result<()> {
         if app.get_flag("bytes") {
             if let Some(bytes) = self.available_bytes() {
-                println!("\n{} megabytes available in the image\n", bytes / 1_048_576);
+                println!(
+                    "\n{} available in the image in {} mode\n",
+                    size(bytes),
+                    self.options.mode
+                );
+                if self.options.ecc > 0 {
+                    println!(
+                        "{} of them hold text with {} check bytes per block\n",
+                        size(self.text_capacity(bytes)),
+                        self.options.ecc
+                    );
+                }
+                if self.options.mode == Mode::Transparent {
+                    let cover = Mode::Cover {
+                        bits: app.get_one::<u8>("bits").copied().unwrap_or(1),
+                        neighbour: false,
+                    };
+                    println!(
+                        "{} bytes available in {cover} mode\n",
+                        available_bytes(&self.image, cover)
+                    );
+                }
+                self.print_compressed_fit(app, bytes)?;
             } else {
                 println!("\nthere are no available bytes in the image\n");
             }
         }
+
+        Ok(())
     }
 
-    fn available_bytes(&self) -> Option<usize> {
-        let Some(_) = self.rgba else {
-            return None;
+    fn print_compressed_fit(&self, app: &ArgMatches, bytes: usize) -> Result<()> {
+        let Some(path) = app.get_one::<PathBuf>("input_text") else {
+            return Ok(());
         };
 
-        Some(self.data.chunks(4).filter(|chunk| chunk[3] == 0).count() * 3)
+        let compression = match self.options.compression {
+            Compression::None => Compression::Zstd,
+            compression => compression,
+        };
+        let text = open_text_file(path)?;
+        let compressed = compression.compress(&text)?.len().max(1);
+        let free = self.text_capacity(bytes);
+
+        println!(
+            "input text: {} bytes, {compressed} bytes compressed with {compression}",
+            text.len()
+        );
+        if compressed <= free {
+            println!("the whole input text fits in the image after compression\n");
+        } else {
+            let fits = (free as u128 * text.len() as u128 / compressed as u128) as usize;
+            println!(
+                "about {fits} of {} bytes of the input text fit after compression\n",
+                text.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Bytes of text fitting in `bytes` behind the header and the check
+    /// bytes of the error correction.
+    fn text_capacity(&self, bytes: usize) -> usize {
+        match self.options.ecc {
+            0 => bytes.saturating_sub(Header::SIZE),
+            parity => ecc::capacity(bytes.saturating_sub(Header::SIZE + CHECK_SIZE), parity),
+        }
+    }
+
+    fn available_bytes(&self) -> Option<usize> {
+        self.check_color_model().ok()?;
+
+        Some(available_bytes(&self.image, self.options.mode))
+    }
+
+    fn print_probe(&self) -> Result<()> {
+        match detect_repairs(&self.image, &self.options)? {
+            Some((header, repaired)) => {
+                println!("\nhidden text found in the image");
+                println!("format version: {}", header.version);
+                let encryption = if header.has(FLAG_ENCRYPTED) {
+                    "passphrase"
+                } else if header.has(FLAG_RECIPIENTS) {
+                    "recipients"
+                } else {
+                    "none"
+                };
+                println!("encryption: {encryption}");
+                match carrier::find(&self.image) {
+                    Some((carrier, _)) => println!("carrier: {carrier}"),
+                    None => println!("mode: {}", header.mode),
+                }
+                match repaired {
+                    Ok(None) => println!("error correction: none"),
+                    Ok(Some(count)) => println!(
+                        "error correction: {} check bytes per block, {count} damaged bytes repaired",
+                        header.ecc
+                    ),
+                    Err(error) => println!(
+                        "error correction: {} check bytes per block, {error}",
+                        header.ecc
+                    ),
+                }
+                println!("declared size: {} bytes\n", header.len);
+            }
+            None => println!("\nno hidden text found in the image\n"),
+        }
+
+        Ok(())
+    }
+
+    fn print_analysis(&self) -> Result<()> {
+        if self.image.depth() > 1 {
+            eprintln!(
+                "warning: the statistical tests expect the low bits to follow the image, \
+                 noise in the low bits of {:?} samples makes their scores unreliable",
+                self.image.color()
+            );
+        }
+        let analysis = analyze(&self.image);
+
+        println!();
+        for finding in &analysis.findings {
+            println!(
+                "{:<22}{:.3}  {}",
+                format!("{}:", finding.test),
+                finding.score,
+                finding.detail
+            );
+        }
+        println!("verdict: {}\n", analysis.verdict());
+
+        Ok(())
     }
 
     fn alpha_max(&mut self, app: &ArgMatches) {
         if app.get_flag("all") {
-            self.data.iter_mut().skip(3).step_by(4).for_each(|alpha| {
-                *alpha = 255;
-            });
+            self.image.make_opaque();
         }
     }
 }
 
-fn open_text_file(path: &PathBuf) -> Result<Vec<u8>> {
+/// Whether `format` stores the samples of [`output_color`] exactly.
+fn is_lossless(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Png
+            | ImageFormat::Tiff
+            | ImageFormat::OpenExr
+            | ImageFormat::WebP
+            | ImageFormat::Qoi
+            | ImageFormat::Bmp
+            | ImageFormat::Tga
+            | ImageFormat::Farbfeld
+    )
+}
 
-    Ok(fs::read(path)?)
+/// Whether `format` keeps the text hidden in the pixels by `mode`. The
+/// robust tiles outlast JPEG compression, which damages a few bytes the
+/// error correction repairs; the verification compares the repaired text.
+fn keeps_pixels(format: ImageFormat, mode: Mode) -> bool {
+    is_lossless(format) || (mode == Mode::Robust && format == ImageFormat::Jpeg)
 }
 
-fn encode_text_len(text: &Vec<u8>) -> Vec<u8> {
-    let mut vec = Vec::from(text.len().to_ne_bytes());
-    vec.insert(3, 0);
-    vec.insert(7, 0);
-    vec.append(&mut vec![0, 0]);
-    vec
+/// Colour type closest to `color` that `format` can store.
+fn output_color(format: ImageFormat, color: ColorType) -> ColorType {
+    match (format, color) {
+        (ImageFormat::Png, ColorType::Rgb32F) => ColorType::Rgb16,
+        (ImageFormat::Png, ColorType::Rgba32F) => ColorType::Rgba16,
+        (ImageFormat::Tiff, ColorType::La8) => ColorType::Rgba8,
+        (ImageFormat::Tiff, ColorType::La16 | ColorType::Rgba32F) => ColorType::Rgba16,
+        (ImageFormat::Tiff, ColorType::Rgb32F) => ColorType::Rgb16,
+        (ImageFormat::Png | ImageFormat::Tiff, color) => color,
+        (ImageFormat::OpenExr, color) if color.has_alpha() => ColorType::Rgba32F,
+        (ImageFormat::OpenExr, _) => ColorType::Rgb32F,
+        (ImageFormat::Farbfeld, _) => ColorType::Rgba16,
+        (ImageFormat::Jpeg, color) => {
+            if color.has_color() {
+                ColorType::Rgb8
+            } else {
+                ColorType::L8
+            }
+        }
+        // QOI only stores colour, BMP and WebP read grey images back as colour.
+        (ImageFormat::WebP | ImageFormat::Qoi | ImageFormat::Bmp | ImageFormat::Tga, color) => {
+            if color.has_alpha() {
+                ColorType::Rgba8
+            } else {
+                ColorType::Rgb8
+            }
+        }
+        (_, ColorType::L8 | ColorType::La8 | ColorType::Rgb8) => color,
+        _ => ColorType::Rgba8,
+    }
+}
+
+/// Why writing hidden text into the pixels of a `format` image fails.
+fn lossy_output(format: ImageFormat) -> String {
+    let name = format_name(format);
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Avif => format!(
+            "{name} is a lossy format: it stores an approximation of the pixels \
+             and loses the low bits holding the hidden text; write a lossless \
+             format such as PNG or WebP, or use the jpeg mode, which hides the \
+             text in the DCT coefficients of a JPEG image"
+        ),
+        ImageFormat::Gif => format!(
+            "{name} reduces the image to 256 colours and loses the low bits \
+             holding the hidden text; write a lossless format such as PNG or WebP"
+        ),
+        _ => "unsupported image output format".to_string(),
+    }
+}
+
+/// `bytes` in megabytes, or in bytes below one megabyte.
+fn size(bytes: usize) -> String {
+    match bytes {
+        0..=1_048_575 => format!("{bytes} bytes"),
+        _ => format!("{} megabytes", bytes / 1_048_576),
+    }
+}
+
+fn format_name(format: ImageFormat) -> String {
+    format.extensions_str()[0].to_uppercase()
+}
+
+/// Colour type and bit depth of the PNG file at `path`, if it is one.
+fn png_color(path: &Path) -> Option<(png::ColorType, png::BitDepth)> {
+    let reader = png::Decoder::new(fs::File::open(path).ok()?)
+        .read_info()
+        .ok()?;
+    let info = reader.info();
+    Some((info.color_type, info.bit_depth))
+}
+
+fn prompt_passphrase(prompt: &str) -> Result<String> {
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if passphrase.is_empty() {
+        return Err("the passphrase must not be empty".into());
+    }
+    Ok(passphrase)
+}
+
+fn keygen(app: &ArgMatches) -> Result<()> {
+    let identity = Identity::generate();
+    let public = identity.to_public();
+    let text = format!("# public key: {public}\n{identity}\n");
+
+    let Some(path) = app.get_one::<PathBuf>("output") else {
+        print!("{text}");
+        return Ok(());
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(text.as_bytes())?;
+    eprintln!("public key: {public}");
+
+    Ok(())
+}
+
+/// Passphrase set in `SUBTXT_PASSPHRASE`, only asked for when `-e` or
+/// encrypted text needs one, so a stray variable never encrypts by itself.
+fn env_passphrase() -> Option<String> {
+    std::env::var("SUBTXT_PASSPHRASE")
+        .ok()
+        .filter(|passphrase| !passphrase.is_empty())
+}
+
+fn new_passphrase() -> Result<String> {
+    let passphrase = prompt_passphrase("passphrase: ")?;
+    if prompt_passphrase("repeat passphrase: ")? != passphrase {
+        return Err("passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+fn open_text_file(path: &PathBuf) -> Result<Vec<u8>> {
+    Ok(fs::read(path)?)
 }
 
 fn main() -> Result<()> {
     let app = app_commands();
     let mut txt_in_img = TxtInImg::new();
+
+    if let Some(("probe", sub)) = app.subcommand() {
+        txt_in_img.open_image(sub)?;
+        txt_in_img.options.key = sub.get_one::<String>("key").cloned();
+        if let Some(mode) = sub.get_one::<String>("mode") {
+            txt_in_img.options.mode = mode.parse()?;
+        }
+        return txt_in_img.print_probe();
+    }
+
+    if let Some(("analyze", sub)) = app.subcommand() {
+        txt_in_img.open_image(sub)?;
+        return txt_in_img.print_analysis();
+    }
+
+    if let Some(("keygen", sub)) = app.subcommand() {
+        return keygen(sub);
+    }
+
+    if let Some((name @ ("add" | "list" | "extract"), sub)) = app.subcommand() {
+        txt_in_img.open_image(sub)?;
+        txt_in_img.read_options(sub, name == "add", true)?;
+        return match name {
+            "add" => txt_in_img.add_files(sub),
+            "list" => txt_in_img.list_files(),
+            _ => txt_in_img.extract_files(sub),
+        };
+    }
+
+    let embedding = app.contains_id("input_text");
+    let extracting =
+        app.get_flag("print") || app.contains_id("output_text") || app.contains_id("extract_to");
+
     txt_in_img.open_image(&app)?;
-    txt_in_img.print_available_bytes(&app);
+    txt_in_img.read_options(&app, embedding, extracting)?;
+    txt_in_img.print_available_bytes(&app)?;
+    txt_in_img.strip_metadata(&app);
     txt_in_img.save_data(&app)?;
     txt_in_img.print_invisible_text(&app)?;
     txt_in_img.save_invisible_text(&app)?;
+    txt_in_img.extract_file(&app)?;
     txt_in_img.alpha_max(&app);
     txt_in_img.save_img(&app)?;
 
@@ -206,14 +799,93 @@ fn app_commands() -> ArgMatches {
         .long_version(crate_version!())
         .author("    by PIC16F877ccs")
         .args_override_self(true)
-        .arg(
-            Arg::new("input_image")
-                .value_name("PAPH")
-                .value_parser(value_parser!(PathBuf))
-                .index(1)
-                .help("Path to input image file")
-                .required(true),
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("probe")
+                .about("Check whether the image carries hidden text")
+                .arg(input_image_arg())
+                .arg(key_arg())
+                .arg(mode_arg()),
         )
+        .subcommand(
+            Command::new("analyze")
+                .about("Estimate how detectable hidden data in the image is")
+                .arg(input_image_arg()),
+        )
+        .subcommand(
+            Command::new("keygen")
+                .about("Generate an identity for recipient encryption")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PAPH")
+                        .help("Identity file to create, printed if omitted")
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("add")
+                .about("Add files to the archive hidden in the image")
+                .arg(input_image_arg())
+                .arg(
+                    Arg::new("files")
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .value_hint(ValueHint::FilePath)
+                        .index(2)
+                        .num_args(1..)
+                        .help("Files to add, replacing files with the same name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PAPH")
+                        .help("Output image file, the input image if omitted")
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1)
+                        .required(false),
+                )
+                .arg(strip_metadata_arg())
+                .args(verify_args())
+                .args(codec_args()),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List the files hidden in the image")
+                .arg(input_image_arg())
+                .args(codec_args()),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Extract files hidden in the image")
+                .arg(input_image_arg())
+                .arg(
+                    Arg::new("name")
+                        .value_name("NAME")
+                        .index(2)
+                        .help("File to extract, all files if omitted")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("extract_to")
+                        .short('x')
+                        .long("extract-to")
+                        .value_name("DIR")
+                        .help("Directory to extract into")
+                        .value_parser(value_parser!(PathBuf))
+                        .value_hint(ValueHint::DirPath)
+                        .default_value(".")
+                        .num_args(1),
+                )
+                .args(codec_args()),
+        )
+        .arg(input_image_arg())
         .arg(
             Arg::new("bytes")
                 .short('b')
@@ -252,6 +924,16 @@ fn app_commands() -> ArgMatches {
                 .help("Print the invisible text")
                 .required(false),
         )
+        .arg(
+            Arg::new("print_format")
+                .long("print-format")
+                .value_name("FORMAT")
+                .value_parser(["text", "hex", "base64"])
+                .default_value("text")
+                .help("How to print the hidden data")
+                .num_args(1)
+                .required(false),
+        )
         .arg(
             Arg::new("ignore")
                 .short('I')
@@ -262,13 +944,27 @@ fn app_commands() -> ArgMatches {
                 .help("Ignore text length")
                 .required(false),
         )
+        .args(codec_args())
+        .mut_arg("encrypt", |arg| arg.requires("input_text"))
+        .mut_arg("recipient", |arg| arg.requires("input_text"))
         .arg(
             Arg::new("output_text")
                 .short('O')
                 .long("output-text")
                 .value_name("PAPH")
-                .help("Output text file")
+                .help("Output text file, or directory to restore the stored file name in")
+                .value_parser(value_parser!(PathBuf))
+                .num_args(1)
+                .required(false),
+        )
+        .arg(
+            Arg::new("extract_to")
+                .short('x')
+                .long("extract-to")
+                .value_name("DIR")
+                .help("Recreate the hidden file in a directory under its stored name")
                 .value_parser(value_parser!(PathBuf))
+                .value_hint(ValueHint::DirPath)
                 .num_args(1)
                 .required(false),
         )
@@ -282,5 +978,181 @@ fn app_commands() -> ArgMatches {
                 .num_args(1)
                 .required(false),
         )
+        .arg(strip_metadata_arg())
+        .args(verify_args())
         .get_matches()
 }
+
+fn verify_args() -> Vec<Arg> {
+    vec![
+        Arg::new("verify")
+            .long("verify")
+            .help("Read the written image back and keep it only if the text reads back (default)")
+            .action(clap::ArgAction::SetTrue)
+            .overrides_with("no_verify"),
+        Arg::new("no_verify")
+            .long("no-verify")
+            .help("Write the image without reading the hidden text back")
+            .action(clap::ArgAction::SetTrue)
+            .overrides_with("verify"),
+    ]
+}
+
+fn strip_metadata_arg() -> Arg {
+    Arg::new("strip_metadata")
+        .long("strip-metadata")
+        .help("Drop the ancillary PNG chunks of the input image, like colour profile and text")
+        .action(clap::ArgAction::SetTrue)
+}
+
+fn input_image_arg() -> Arg {
+    Arg::new("input_image")
+        .value_name("PAPH")
+        .value_parser(value_parser!(PathBuf))
+        .index(1)
+        .help("Path to input image file")
+        .required(true)
+}
+
+fn print_report(report: &Report, options: &Options) {
+    if options.carrier != Carrier::Pixels {
+        eprintln!("{} bytes stored in the {}", report.written, options.carrier);
+        return;
+    }
+    // Every tile repeats the whole text, so a rate per bit says nothing.
+    if options.mode == Mode::Robust {
+        eprintln!("{} samples changed", report.changed);
+        return;
+    }
+    eprintln!(
+        "{} {} changed, {:.3} changes per embedded bit",
+        report.changed,
+        match options.mode {
+            Mode::Jpeg => "coefficients",
+            _ => "samples",
+        },
+        report.change_rate()
+    );
+}
+
+/// Tells how many damaged bytes the error correction repaired.
+fn print_corrections(repaired: Option<usize>) {
+    if let Some(count) = repaired {
+        eprintln!("{count} damaged bytes repaired by the error correction");
+    }
+}
+
+fn key_arg() -> Arg {
+    Arg::new("key")
+        .short('K')
+        .long("key")
+        .value_name("KEY")
+        .env("SUBTXT_KEY")
+        .hide_env_values(true)
+        .help("Key scattering the text over the image in a pseudo-random order")
+        .num_args(1)
+        .required(false)
+}
+
+fn mode_arg() -> Arg {
+    Arg::new("mode")
+        .short('m')
+        .long("mode")
+        .value_name("MODE")
+        .value_parser([
+            "transparent",
+            "lsb",
+            "matching",
+            "hamming",
+            "cover",
+            "palette",
+            "jpeg",
+            "robust",
+        ])
+        .help("Pixels that carry the text, transparent ones or the low bits of all, JPEG coefficients, or tiles surviving cropping and resizing")
+        .num_args(1)
+        .required(false)
+}
+
+fn codec_args() -> Vec<Arg> {
+    vec![
+        key_arg(),
+        mode_arg(),
+        Arg::new("carrier")
+            .long("carrier")
+            .value_name("CARRIER")
+            .value_parser(["pixels", "chunk", "ztxt", "itxt"])
+            .help("Where the text goes: pixels, a private PNG chunk or a zTXt or iTXt text chunk")
+            .num_args(1)
+            .required(false),
+        Arg::new("bits")
+            .long("bits")
+            .value_name("N")
+            .value_parser(value_parser!(u8).range(1..=8))
+            .help("Low bits per sample in the lsb and cover modes (1), bits per 2^N-1 samples in hamming (3)")
+            .num_args(1)
+            .required(false),
+        Arg::new("alpha")
+            .long("alpha")
+            .action(clap::ArgAction::SetTrue)
+            .num_args(0)
+            .help("Also use the alpha channel in the lsb, matching and hamming modes")
+            .required(false),
+        Arg::new("neighbour")
+            .long("neighbour")
+            .action(clap::ArgAction::SetTrue)
+            .num_args(0)
+            .help("Recolour transparent pixels like their nearest opaque pixel in the cover mode")
+            .required(false),
+        Arg::new("compress")
+            .short('c')
+            .long("compress")
+            .value_name("ALGORITHM")
+            .value_parser(["zstd", "deflate", "none"])
+            .default_missing_value("zstd")
+            .help("Compress the text before hiding it")
+            .num_args(0..=1)
+            .required(false),
+        Arg::new("ecc")
+            .long("ecc")
+            .value_name("BYTES")
+            .value_parser(value_parser!(u8).range(2..=128))
+            .default_missing_value("32")
+            .help("Add BYTES Reed-Solomon check bytes to every 255-byte block, repairing up to half as many damaged bytes")
+            .num_args(0..=1)
+            .required(false),
+        Arg::new("encrypt")
+            .short('e')
+            .long("encrypt")
+            .action(clap::ArgAction::SetTrue)
+            .num_args(0)
+            .help("Encrypt the text with a passphrase")
+            .required(false),
+        Arg::new("passphrase")
+            .short('P')
+            .long("passphrase")
+            .value_name("PASS")
+            .help("Passphrase to encrypt or decrypt the text, taken from SUBTXT_PASSPHRASE or prompted if omitted")
+            .num_args(1)
+            .required(false),
+        Arg::new("recipient")
+            .short('r')
+            .long("recipient")
+            .value_name("KEY")
+            .conflicts_with_all(["encrypt", "passphrase"])
+            .action(clap::ArgAction::Append)
+            .help("Encrypt the text for a public key or a recipients file")
+            .num_args(1)
+            .required(false),
+        Arg::new("identity")
+            .short('k')
+            .long("identity")
+            .value_name("PAPH")
+            .action(clap::ArgAction::Append)
+            .help("Identity file to decrypt the text")
+            .value_parser(value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .num_args(1)
+            .required(false),
+    ]
+}
@@ -0,0 +1,242 @@
+//! Metadata stored in front of the hidden data.
+//!
+//! The record is a list of fields, each a tag byte, a little-endian `u16`
+//! length and the value, preceded by the little-endian `u16` length of
+//! the whole list. Unknown tags are skipped so newer fields can be added
+//! without breaking older readers.
+
+use crate::Result;
+use std::fs;
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TAG_CONTENT_TYPE: u8 = 1;
+const TAG_NAME: u8 = 2;
+const TAG_SIZE: u8 = 3;
+const TAG_MODIFIED: u8 = 4;
+const TAG_MODE: u8 = 5;
+const TRUNCATED: &str = "metadata is truncated";
+
+/// Content type recorded for UTF-8 text.
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+/// Content type recorded for anything else.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// MIME type of the data.
+    pub content_type: Option<String>,
+    /// Original file name of the data.
+    pub name: Option<String>,
+    /// Original size of the data in bytes.
+    pub size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch.
+    pub modified: Option<u64>,
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+}
+
+impl Metadata {
+    /// Metadata describing `data`, with the content type guessed from it.
+    pub fn for_data(data: &[u8]) -> Metadata {
+        let content_type = match std::str::from_utf8(data) {
+            Ok(_) => TEXT_PLAIN,
+            Err(_) => OCTET_STREAM,
+        };
+
+        Metadata {
+            content_type: Some(content_type.to_string()),
+            size: Some(data.len() as u64),
+            ..Metadata::default()
+        }
+    }
+
+    /// Metadata describing the file at `path` holding `data`.
+    pub fn for_file(path: &Path, data: &[u8]) -> Result<Metadata> {
+        let file = fs::metadata(path)?;
+        let mut metadata = Metadata::for_data(data);
+
+        metadata.name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        metadata.modified = file
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        #[cfg(unix)]
+        {
+            metadata.mode = Some(std::os::unix::fs::PermissionsExt::mode(&file.permissions()));
+        }
+
+        Ok(metadata)
+    }
+
+    /// The stored file name, refusing names that would leave the target
+    /// directory such as absolute paths, `..` or names with separators.
+    pub fn file_name(&self) -> Result<&str> {
+        let Some(name) = self.name.as_deref() else {
+            return Err("the hidden data has no stored file name".into());
+        };
+
+        let mut components = Path::new(name).components();
+        let single =
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+        if !single || name.contains(['/', '\\', '\0']) {
+            return Err(format!("refusing unsafe stored file name {name:?}").into());
+        }
+
+        Ok(name)
+    }
+
+    /// Modification time as [`SystemTime`].
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        UNIX_EPOCH.checked_add(Duration::from_secs(self.modified?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Whether the content type marks the data as text.
+    pub fn is_text(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_none_or(|content_type| content_type.starts_with("text/"))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut fields = Vec::new();
+        let mut push = |tag: u8, value: &[u8]| -> Result<()> {
+            let Ok(len) = u16::try_from(value.len()) else {
+                return Err("metadata field is too long".into());
+            };
+            fields.push(tag);
+            fields.extend_from_slice(&len.to_le_bytes());
+            fields.extend_from_slice(value);
+            Ok(())
+        };
+
+        if let Some(content_type) = &self.content_type {
+            push(TAG_CONTENT_TYPE, content_type.as_bytes())?;
+        }
+        if let Some(name) = &self.name {
+            push(TAG_NAME, name.as_bytes())?;
+        }
+        if let Some(size) = self.size {
+            push(TAG_SIZE, &size.to_le_bytes())?;
+        }
+        if let Some(modified) = self.modified {
+            push(TAG_MODIFIED, &modified.to_le_bytes())?;
+        }
+        if let Some(mode) = self.mode {
+            push(TAG_MODE, &mode.to_le_bytes())?;
+        }
+
+        let Ok(len) = u16::try_from(fields.len()) else {
+            return Err("metadata is too long".into());
+        };
+        let mut bytes = len.to_le_bytes().to_vec();
+        bytes.append(&mut fields);
+        Ok(bytes)
+    }
+
+    /// Parses a record written by [`Metadata::to_bytes`], returning it with
+    /// the number of bytes it used.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Metadata, usize)> {
+        let len = read_u16(bytes, 0).ok_or(TRUNCATED)? as usize;
+        let fields = bytes.get(2..2 + len).ok_or(TRUNCATED)?;
+
+        let mut metadata = Metadata::default();
+        let mut at = 0;
+        while at < fields.len() {
+            let tag = fields[at];
+            let size = read_u16(fields, at + 1).ok_or(TRUNCATED)? as usize;
+            let value = fields.get(at + 3..at + 3 + size).ok_or(TRUNCATED)?;
+            let text = || String::from_utf8(value.to_vec());
+            let number = || -> Result<u64> {
+                let mut bytes = [0; 8];
+                bytes
+                    .get_mut(..value.len())
+                    .ok_or("metadata field is too long")?
+                    .copy_from_slice(value);
+                Ok(u64::from_le_bytes(bytes))
+            };
+
+            match tag {
+                TAG_CONTENT_TYPE => metadata.content_type = Some(text()?),
+                TAG_NAME => metadata.name = Some(text()?),
+                TAG_SIZE => metadata.size = Some(number()?),
+                TAG_MODIFIED => metadata.modified = Some(number()?),
+                TAG_MODE => metadata.mode = Some(number()? as u32),
+                _ => {}
+            }
+            at += 3 + size;
+        }
+
+        Ok((metadata, 2 + len))
+    }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(at..at + 2)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Payload;
+
+    fn named(name: &str) -> Metadata {
+        Metadata {
+            name: Some(name.to_string()),
+            ..Metadata::default()
+        }
+    }
+
+    #[test]
+    fn plain_names_are_kept() {
+        assert_eq!(named("notes.txt").file_name().unwrap(), "notes.txt");
+        assert_eq!(named(".hidden").file_name().unwrap(), ".hidden");
+    }
+
+    #[test]
+    fn unsafe_names_are_refused() {
+        for name in [
+            "..",
+            ".",
+            "",
+            "/etc/passwd",
+            "\\\\server\\share",
+            "C:\\Windows",
+            "dir/notes.txt",
+            "../notes.txt",
+            "dir\\notes.txt",
+            "notes.txt/",
+            "notes\0.txt",
+        ] {
+            assert!(named(name).file_name().is_err(), "{name:?}");
+        }
+        assert!(Metadata::default().file_name().is_err());
+    }
+
+    #[test]
+    fn writing_does_not_overwrite() {
+        let dir = std::env::temp_dir().join(format!("subtxt-meta-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let payload = |data: &[u8]| Payload {
+            metadata: named("notes.txt"),
+            data: data.to_vec(),
+        };
+
+        let path = payload(b"first").write_to(&dir).unwrap();
+        assert_eq!(path, dir.join("notes.txt"));
+        assert!(payload(b"second").write_to(&dir).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,666 @@
+//! Embedding modes deciding which bits of the image carry the payload.
+//!
+//! Every mode describes the image as a list of slots, one byte of a sample
+//! each: the least significant byte of 16-bit and float samples, or every
+//! byte of integer samples (the two low bytes of float ones) in transparent
+//! pixels. Slots are grouped by the coding of the mode: a single slot
+//! holding its `bits` least significant bits for plain replacement and LSB
+//! matching, or `2^bits - 1` slots holding `bits` bits in the syndrome of
+//! their lowest bits for Hamming matrix encoding. The palette mode treats
+//! the indices of an indexed image as samples, the jpeg mode uses the DCT
+//! coefficients of [`crate::jpeg`] instead and the robust mode the tiles of
+//! [`crate::robust`]. The payload is written as a
+//! bit stream, least significant bit of every byte first, filling the
+//! groups in order. Encoder, decoder and capacity all go through
+//! [`Layout`], so they always agree on which samples are used.
+//!
+//! With a key the slots are visited in a keyed pseudo-random order instead
+//! of raster order: the key is hashed into the seed of a ChaCha20 generator
+//! driving a Fisher-Yates shuffle of the slots. Only the shuffled prefix
+//! that is actually visited is generated.
+
+use crate::{Canvas, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Whole colour samples of fully transparent pixels.
+    #[default]
+    Transparent,
+    /// The `bits` least significant bits of the colour samples of every pixel,
+    /// and of the alpha sample when `alpha` is set.
+    Lsb { bits: u8, alpha: bool },
+    /// The least significant bit of the same samples as [`Mode::Lsb`],
+    /// changed by adding or subtracting one instead of being overwritten.
+    Matching { alpha: bool },
+    /// `bits` payload bits in the least significant bits of every group of
+    /// `2^bits - 1` samples, changing at most one sample per group by one
+    /// (Hamming matrix encoding).
+    Hamming { bits: u8, alpha: bool },
+    /// The `bits` least significant bits of the colour samples of fully
+    /// transparent pixels, keeping the rest of their colour. With
+    /// `neighbour` set, transparent pixels first take the colour of their
+    /// nearest opaque pixel.
+    Cover { bits: u8, neighbour: bool },
+    /// The lowest bit of the index of every pixel of an indexed image whose
+    /// colour has a twin entry in the palette, see
+    /// [`Canvas::pair_palette`].
+    Palette,
+    /// The parity of the non-zero AC coefficients of a JPEG image, see
+    /// [`crate::jpeg`].
+    Jpeg,
+    /// A faint pattern repeated in tiles, surviving cropping and mild
+    /// rescaling, see [`crate::robust`].
+    Robust,
+}
+
+impl Mode {
+    const TRANSPARENT: u8 = 0;
+    const LSB: u8 = 1;
+    const MATCHING: u8 = 2;
+    const HAMMING: u8 = 3;
+    const COVER: u8 = 4;
+    const PALETTE: u8 = 5;
+    const JPEG: u8 = 6;
+    const ROBUST: u8 = 7;
+    const ALPHA: u8 = 0x80;
+    const NEIGHBOUR: u8 = 0x80;
+
+    /// Mode identifier and parameter recorded in the [`crate::Header`].
+    pub fn to_header(self) -> (u8, u8) {
+        let alpha = |alpha| if alpha { Mode::ALPHA } else { 0 };
+        match self {
+            Mode::Transparent => (Mode::TRANSPARENT, 0),
+            Mode::Lsb { bits, alpha: a } => (Mode::LSB, bits | alpha(a)),
+            Mode::Matching { alpha: a } => (Mode::MATCHING, alpha(a)),
+            Mode::Hamming { bits, alpha: a } => (Mode::HAMMING, bits | alpha(a)),
+            Mode::Cover { bits, neighbour } => (
+                Mode::COVER,
+                bits | if neighbour { Mode::NEIGHBOUR } else { 0 },
+            ),
+            Mode::Palette => (Mode::PALETTE, 0),
+            Mode::Jpeg => (Mode::JPEG, 0),
+            Mode::Robust => (Mode::ROBUST, 0),
+        }
+    }
+
+    pub fn from_header(id: u8, param: u8) -> Result<Mode> {
+        let bits = param & !Mode::ALPHA;
+        let alpha = param & Mode::ALPHA != 0;
+        let mode = match id {
+            Mode::TRANSPARENT => Mode::Transparent,
+            Mode::LSB => Mode::Lsb { bits, alpha },
+            Mode::MATCHING => Mode::Matching { alpha },
+            Mode::HAMMING => Mode::Hamming { bits, alpha },
+            Mode::COVER => Mode::Cover {
+                bits: param & !Mode::NEIGHBOUR,
+                neighbour: param & Mode::NEIGHBOUR != 0,
+            },
+            Mode::PALETTE => Mode::Palette,
+            Mode::JPEG => Mode::Jpeg,
+            Mode::ROBUST => Mode::Robust,
+            _ => return Err(format!("unsupported embedding mode {id}").into()),
+        };
+        mode.validate()?;
+        Ok(mode)
+    }
+
+    pub fn validate(self) -> Result<()> {
+        match self {
+            Mode::Lsb { bits, .. } | Mode::Hamming { bits, .. } | Mode::Cover { bits, .. }
+                if !(1..=8).contains(&bits) =>
+            {
+                Err("the number of bits must be between 1 and 8".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Every mode tried when looking for a payload.
+    pub fn candidates() -> Vec<Mode> {
+        let mut modes = vec![Mode::Transparent];
+        for alpha in [false, true] {
+            modes.extend((1..=8).map(|bits| Mode::Lsb { bits, alpha }));
+            modes.push(Mode::Matching { alpha });
+            modes.extend((1..=8).map(|bits| Mode::Hamming { bits, alpha }));
+        }
+        for neighbour in [false, true] {
+            modes.extend((1..=8).map(|bits| Mode::Cover { bits, neighbour }));
+        }
+        modes.push(Mode::Palette);
+        modes.push(Mode::Jpeg);
+        modes.push(Mode::Robust);
+        modes
+    }
+
+    /// Whether the mode only uses fully transparent pixels.
+    pub fn is_transparent(self) -> bool {
+        matches!(self, Mode::Transparent | Mode::Cover { .. })
+    }
+
+    /// Whether the mode needs an alpha channel in the image.
+    pub fn needs_alpha(self) -> bool {
+        match self {
+            Mode::Transparent | Mode::Cover { .. } => true,
+            Mode::Palette | Mode::Jpeg | Mode::Robust => false,
+            Mode::Lsb { alpha, .. } | Mode::Matching { alpha } | Mode::Hamming { alpha, .. } => {
+                alpha
+            }
+        }
+    }
+
+    fn coding(self) -> Coding {
+        match self {
+            Mode::Transparent => Coding::Replace(8),
+            Mode::Lsb { bits, .. } => Coding::Replace(bits),
+            Mode::Matching { .. } => Coding::Matching,
+            Mode::Hamming { bits, .. } => Coding::Hamming(bits),
+            Mode::Cover { bits, .. } => Coding::Replace(bits),
+            Mode::Palette | Mode::Jpeg | Mode::Robust => Coding::Replace(1),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let channels = |alpha| if alpha { "colour and alpha" } else { "colour" };
+        match self {
+            Mode::Transparent => f.write_str("transparent"),
+            Mode::Lsb { bits, alpha } => write!(f, "lsb, {bits} bits of {}", channels(*alpha)),
+            Mode::Matching { alpha } => write!(f, "lsb matching of {}", channels(*alpha)),
+            Mode::Hamming { bits, alpha } => write!(
+                f,
+                "hamming, {bits} bits per {} samples of {}",
+                (1 << bits) - 1,
+                channels(*alpha)
+            ),
+            Mode::Cover { bits, neighbour } => write!(
+                f,
+                "cover, {bits} bits of transparent colour{}",
+                if *neighbour {
+                    " in neighbour colours"
+                } else {
+                    ""
+                }
+            ),
+            Mode::Palette => f.write_str("palette"),
+            Mode::Jpeg => f.write_str("jpeg, parity of DCT coefficients"),
+            Mode::Robust => f.write_str("robust, tiles with a locator pattern"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Mode> {
+        match name {
+            "transparent" => Ok(Mode::Transparent),
+            "lsb" => Ok(Mode::Lsb {
+                bits: 1,
+                alpha: false,
+            }),
+            "matching" => Ok(Mode::Matching { alpha: false }),
+            "hamming" => Ok(Mode::Hamming {
+                bits: 3,
+                alpha: false,
+            }),
+            "cover" => Ok(Mode::Cover {
+                bits: 1,
+                neighbour: false,
+            }),
+            "palette" => Ok(Mode::Palette),
+            "jpeg" => Ok(Mode::Jpeg),
+            "robust" => Ok(Mode::Robust),
+            _ => Err(format!("unknown embedding mode {name}").into()),
+        }
+    }
+}
+
+/// How payload bits are stored in a group of slots.
+#[derive(Debug, Clone, Copy)]
+enum Coding {
+    /// The given number of low bits of one slot are overwritten.
+    Replace(u8),
+    /// The lowest bit of one slot is matched by a change of one.
+    Matching,
+    /// The bits are the syndrome of the lowest bits of `2^bits - 1` slots.
+    Hamming(u8),
+}
+
+impl Coding {
+    /// Slots in a group and payload bits the group carries.
+    fn group(self) -> (usize, usize) {
+        match self {
+            Coding::Replace(bits) => (1, bits as usize),
+            Coding::Matching => (1, 1),
+            Coding::Hamming(bits) => ((1 << bits) - 1, bits as usize),
+        }
+    }
+}
+
+/// Seed of the slot order derived from `key`.
+pub(crate) fn seed(key: &str) -> [u8; 32] {
+    Sha256::new_with_prefix("subtxt slot order")
+        .chain_update(key)
+        .finalize()
+        .into()
+}
+
+/// Slots `0..len` in raster order, or shuffled by the generator seeded
+/// with `seed`, generated as they are visited.
+pub(crate) struct Order {
+    at: usize,
+    len: usize,
+    rng: Option<ChaCha20Rng>,
+    swapped: HashMap<usize, usize>,
+}
+
+impl Order {
+    pub(crate) fn new(len: usize, seed: Option<[u8; 32]>) -> Order {
+        Order {
+            at: 0,
+            len,
+            rng: seed.map(ChaCha20Rng::from_seed),
+            swapped: HashMap::new(),
+        }
+    }
+}
+
+impl Iterator for Order {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let at = self.at;
+        if at >= self.len {
+            return None;
+        }
+        self.at += 1;
+        let Some(rng) = &mut self.rng else {
+            return Some(at);
+        };
+
+        let other = rng.gen_range(at..self.len);
+        let slot = *self.swapped.get(&other).unwrap_or(&other);
+        self.swapped
+            .insert(other, *self.swapped.get(&at).unwrap_or(&at));
+        Some(slot)
+    }
+}
+
+/// Sample bytes of a [`Canvas`] used by a [`Mode`].
+pub struct Layout {
+    /// Eligible pixels, or every pixel when `None`.
+    pixels: Option<Vec<u32>>,
+    count: usize,
+    /// Bytes per pixel.
+    stride: usize,
+    /// Offsets of the slots within a pixel.
+    offsets: Vec<usize>,
+    coding: Coding,
+    seed: Option<[u8; 32]>,
+}
+
+impl Layout {
+    /// Slots of `canvas` used by `mode`, visited in an order derived from
+    /// `key` when one is given.
+    pub fn new(canvas: &Canvas, mode: Mode, key: Option<&str>) -> Layout {
+        let depth = canvas.depth();
+        let (channels, bytes) = match mode {
+            Mode::Transparent if canvas.is_float() => (canvas.colour_channels(), 2),
+            Mode::Transparent => (canvas.colour_channels(), depth),
+            Mode::Cover { .. } | Mode::Palette => (canvas.colour_channels(), 1),
+            // The coefficients and tiles are not slots, see `crate::jpeg`
+            // and `crate::robust`.
+            Mode::Jpeg | Mode::Robust => (0, 1),
+            Mode::Lsb { alpha, .. } | Mode::Matching { alpha } | Mode::Hamming { alpha, .. } => {
+                match alpha {
+                    true => (canvas.channels(), 1),
+                    false => (canvas.colour_channels(), 1),
+                }
+            }
+        };
+        let eligible = |pixel| match mode {
+            Mode::Palette => canvas.is_paired(pixel),
+            _ => canvas.is_transparent(pixel),
+        };
+        let pixels = (mode.is_transparent() || mode == Mode::Palette).then(|| {
+            (0..canvas.len())
+                .filter(|&pixel| eligible(pixel))
+                .map(|pixel| pixel as u32)
+                .collect::<Vec<_>>()
+        });
+
+        Layout {
+            count: pixels.as_ref().map_or(canvas.len(), Vec::len),
+            pixels,
+            stride: canvas.channels() * depth,
+            offsets: (0..channels)
+                .flat_map(|channel| (0..bytes).map(move |byte| channel * depth + byte))
+                .collect(),
+            coding: mode.coding(),
+            seed: key.map(seed),
+        }
+    }
+
+    /// The first `count` slots in embedding order.
+    pub fn order(&self, count: usize) -> Vec<usize> {
+        Order::new(self.len(), self.seed).take(count).collect()
+    }
+
+    /// Number of slots.
+    pub fn len(&self) -> usize {
+        self.count * self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of whole bytes the slots can hold.
+    pub fn capacity(&self) -> usize {
+        let (slots, bits) = self.coding.group();
+        self.len() / slots * bits / 8
+    }
+
+    /// Index in the sample buffer of `slot`.
+    pub fn index(&self, slot: usize) -> usize {
+        let pixel = slot / self.offsets.len();
+        let pixel = match &self.pixels {
+            Some(pixels) => pixels[pixel] as usize,
+            None => pixel,
+        };
+        pixel * self.stride + self.offsets[slot % self.offsets.len()]
+    }
+
+    /// Payload bits held by the group of `slots`.
+    fn value(&self, data: &[u8], slots: &[usize]) -> u32 {
+        let sample = |slot| data[self.index(slot)] as u32;
+        match self.coding {
+            Coding::Replace(bits) => sample(slots[0]) & ((1 << bits) - 1),
+            Coding::Matching => sample(slots[0]) & 1,
+            Coding::Hamming(_) => slots
+                .iter()
+                .enumerate()
+                .filter(|(_, &slot)| sample(slot) & 1 == 1)
+                .fold(0, |syndrome, (at, _)| syndrome ^ (at as u32 + 1)),
+        }
+    }
+
+    /// Makes the group of `slots` hold `value`, returning the number of
+    /// samples changed.
+    fn set(&self, data: &mut [u8], slots: &[usize], value: u32, rng: &mut impl Rng) -> usize {
+        let target = match self.coding {
+            Coding::Replace(bits) => {
+                let sample = &mut data[self.index(slots[0])];
+                let replaced = *sample & !((1u16 << bits) - 1) as u8 | value as u8;
+                let changed = replaced != *sample;
+                *sample = replaced;
+                return changed as usize;
+            }
+            Coding::Matching => {
+                if self.value(data, slots) == value {
+                    return 0;
+                }
+                slots[0]
+            }
+            Coding::Hamming(_) => {
+                let diff = self.value(data, slots) ^ value;
+                if diff == 0 {
+                    return 0;
+                }
+                slots[diff as usize - 1]
+            }
+        };
+
+        let sample = &mut data[self.index(target)];
+        *sample = match *sample {
+            0 => 1,
+            255 => 254,
+            sample if rng.gen() => sample + 1,
+            sample => sample - 1,
+        };
+        1
+    }
+
+    /// Writes `bytes` into the slots, returning the number of whole bytes
+    /// that fit and the number of samples changed.
+    pub fn write(&self, data: &mut [u8], bytes: &[u8]) -> (usize, usize) {
+        let written = bytes.len().min(self.capacity());
+        let (slots, bits) = self.coding.group();
+        let total = written * 8;
+        let order = self.order(total.div_ceil(bits) * slots);
+        let mut rng = rand::thread_rng();
+        let mut changed = 0;
+
+        for (group, slots) in order.chunks(slots).enumerate() {
+            let mut value = self.value(data, slots);
+            for bit in 0..bits {
+                let position = group * bits + bit;
+                if position < total {
+                    let set = (bytes[position / 8] >> (position % 8) & 1) as u32;
+                    value = value & !(1 << bit) | set << bit;
+                }
+            }
+            changed += self.set(data, slots, value, &mut rng);
+        }
+
+        (written, changed)
+    }
+
+    /// Reads `len` bytes starting `offset` bytes into the slots.
+    pub fn read(&self, data: &[u8], offset: usize, len: usize) -> Vec<u8> {
+        let len = len.min(self.capacity().saturating_sub(offset));
+        let (slots, bits) = self.coding.group();
+        let positions = offset * 8..(offset + len) * 8;
+        let order = self.order(positions.end.div_ceil(bits) * slots);
+        let mut bytes = vec![0; len];
+
+        for group in positions.start / bits..positions.end.div_ceil(bits) {
+            let value = self.value(data, &order[group * slots..(group + 1) * slots]);
+            for bit in 0..bits {
+                let position = group * bits + bit;
+                if positions.contains(&position) {
+                    bytes[position / 8 - offset] |= ((value >> bit & 1) as u8) << (position % 8);
+                }
+            }
+        }
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    /// Random colour, fully transparent in about half of the pixels.
+    fn canvas(seed: u64) -> Canvas {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        Canvas::from(RgbaImage::from_fn(40, 30, |_, _| {
+            let alpha = if rng.gen() { 0 } else { 255 };
+            Rgba([rng.gen(), rng.gen(), rng.gen(), alpha])
+        }))
+    }
+
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        ChaCha20Rng::seed_from_u64(seed).fill(&mut bytes[..]);
+        bytes
+    }
+
+    /// Writes as many bytes as fit with `mode`, with and without a key, and
+    /// checks they read back, returning the samples before and after.
+    fn round_trip(mode: Mode) -> Vec<(Vec<u8>, Vec<u8>)> {
+        [None, Some("key")]
+            .into_iter()
+            .map(|key| {
+                let canvas = canvas(1);
+                let layout = Layout::new(&canvas, mode, key);
+                let bytes = random(layout.capacity(), 2);
+                let mut data = canvas.samples().to_vec();
+                assert_eq!(layout.write(&mut data, &bytes).0, bytes.len(), "{mode}");
+                assert_eq!(layout.read(&data, 0, bytes.len()), bytes, "{mode} {key:?}");
+                assert_eq!(layout.read(&data, 5, 7), bytes[5..12], "{mode} {key:?}");
+                (canvas.samples().to_vec(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mode_is_recorded_in_the_header() {
+        for mode in Mode::candidates() {
+            let (id, param) = mode.to_header();
+            assert_eq!(Mode::from_header(id, param).unwrap(), mode);
+        }
+        assert!(Mode::from_header(Mode::LSB, 9).is_err());
+        assert!(Mode::from_header(99, 0).is_err());
+    }
+
+    #[test]
+    fn transparent_round_trip() {
+        for (before, after) in round_trip(Mode::Transparent) {
+            for (old, new) in before.chunks(4).zip(after.chunks(4)) {
+                assert_eq!(old[3], new[3]);
+                if old[3] != 0 {
+                    assert_eq!(old, new);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lsb_round_trip() {
+        for alpha in [false, true] {
+            for bits in 1..=8 {
+                for (before, after) in round_trip(Mode::Lsb { bits, alpha }) {
+                    for (at, (old, new)) in before.iter().zip(&after).enumerate() {
+                        let kept = if !alpha && at % 4 == 3 { 0 } else { bits };
+                        assert_eq!(*old as u16 >> kept, *new as u16 >> kept);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn order_without_a_key_is_raster_order() {
+        assert!(Order::new(100, None).eq(0..100));
+    }
+
+    #[test]
+    fn keyed_order_is_a_permutation() {
+        let mut order = Order::new(1000, Some(seed("key"))).collect::<Vec<_>>();
+        assert_ne!(order, (0..1000).collect::<Vec<_>>());
+        order.sort_unstable();
+        assert_eq!(order, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn keyed_order_depends_on_the_key() {
+        let order = |key| {
+            Order::new(1000, Some(seed(key)))
+                .take(50)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order("key"), order("key"));
+        assert_ne!(order("key"), order("other key"));
+    }
+
+    #[test]
+    fn wrong_key_reads_other_slots() {
+        let canvas = canvas(1);
+        let mode = Mode::Lsb {
+            bits: 1,
+            alpha: false,
+        };
+        let layout = Layout::new(&canvas, mode, Some("key"));
+        let bytes = random(64, 2);
+        let mut data = canvas.samples().to_vec();
+        layout.write(&mut data, &bytes);
+        let other = Layout::new(&canvas, mode, Some("other key"));
+        assert_ne!(other.read(&data, 0, bytes.len()), bytes);
+    }
+
+    #[test]
+    fn matching_changes_samples_by_one() {
+        for alpha in [false, true] {
+            for (before, after) in round_trip(Mode::Matching { alpha }) {
+                for (at, (&old, &new)) in before.iter().zip(&after).enumerate() {
+                    assert!(old.abs_diff(new) <= 1);
+                    if !alpha && at % 4 == 3 {
+                        assert_eq!(old, new);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hamming_round_trip() {
+        for alpha in [false, true] {
+            for bits in 1..=8 {
+                for (before, after) in round_trip(Mode::Hamming { bits, alpha }) {
+                    for (&old, &new) in before.iter().zip(&after) {
+                        assert!(old.abs_diff(new) <= 1);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hamming_changes_one_sample_per_group() {
+        for key in [None, Some("key")] {
+            for bits in 2..=6 {
+                let canvas = canvas(3);
+                let layout = Layout::new(&canvas, Mode::Hamming { bits, alpha: false }, key);
+                let bytes = random(layout.capacity(), 4);
+                let mut data = canvas.samples().to_vec();
+                let (_, changed) = layout.write(&mut data, &bytes);
+
+                let group = (1 << bits) - 1;
+                let slots = layout.order((layout.capacity() * 8).div_ceil(bits as usize) * group);
+                let mut total = 0;
+                for slots in slots.chunks(group) {
+                    let changes = slots
+                        .iter()
+                        .filter(|&&slot| {
+                            let index = layout.index(slot);
+                            data[index] != canvas.samples()[index]
+                        })
+                        .count();
+                    assert!(changes <= 1, "{changes} changes in a group of {group}");
+                    total += changes;
+                }
+                assert_eq!(total, changed);
+            }
+        }
+    }
+
+    #[test]
+    fn cover_touches_only_transparent_pixels() {
+        for neighbour in [false, true] {
+            for bits in 1..=8 {
+                for (before, after) in round_trip(Mode::Cover { bits, neighbour }) {
+                    for (old, new) in before.chunks(4).zip(after.chunks(4)) {
+                        assert_eq!(old[3], new[3]);
+                        match old[3] {
+                            0 => assert!(old
+                                .iter()
+                                .zip(new)
+                                .all(|(old, new)| { *old as u16 >> bits == *new as u16 >> bits })),
+                            _ => assert_eq!(old, new),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
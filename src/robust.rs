@@ -0,0 +1,478 @@
+//! The robust mode, a faint pattern repeated in tiles that survives
+//! cropping and mild rescaling.
+//!
+//! Every tile of [`TILE`] × [`TILE`] pixels is cut into cells of [`CELL`]
+//! × [`CELL`] pixels, and every cell adds or subtracts [`STRENGTH`] to the
+//! colour of its pixels. An eighth of the cells form a locator with a known
+//! sign, the others carry the payload, every bit in [`SPREAD`] cells whose
+//! sign is a pseudo-random chip flipped for a zero bit. The cells are placed
+//! and the chips drawn from a generator seeded with the key, and every tile
+//! repeats the same payload.
+//!
+//! The reader removes the image itself with a high-pass filter, finds the
+//! size of the tiles from the autocorrelation of what is left, folds all
+//! tiles onto one and looks for the shift at which the locator matches
+//! best. The payload is read at that shift, every bit from the sum over all
+//! its cells in all tiles, so a copy keeping about one and a half tiles each
+//! way still holds it. Use [`crate::ecc`] to repair the bits read wrong.
+
+use crate::{Canvas, Result};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+/// Pixels per side of a cell.
+const CELL: usize = 4;
+/// Cells per side of a tile.
+const CELLS: usize = 64;
+/// Pixels per side of a tile.
+pub const TILE: usize = CELL * CELLS;
+/// Cells of the locator.
+const LOCATOR: usize = CELLS * CELLS / 8;
+/// Cells carrying every bit.
+const SPREAD: usize = 2;
+/// Change of the 8-bit colour samples in every cell.
+const STRENGTH: i32 = 4;
+/// Bytes every tile holds.
+pub const CAPACITY: usize = (CELLS * CELLS - LOCATOR) / SPREAD / 8;
+/// Check bytes per block of [`crate::ecc`] used unless asked otherwise.
+pub const ECC: u8 = 64;
+
+/// Radius of the box filter removing the image before reading.
+const RADIUS: usize = 3;
+/// Tile sizes, relative to [`TILE`], searched for in a rescaled copy.
+const SCALES: (f64, f64) = (0.6, 1.6);
+/// Pixels overlapping at least at every searched tile size.
+const OVERLAP: usize = 64;
+
+/// Cells of the locator and of the bits, and the chip of every cell.
+struct Pattern {
+    cells: Vec<usize>,
+    chips: Vec<bool>,
+}
+
+impl Pattern {
+    fn new(key: Option<&str>) -> Pattern {
+        let seed = Sha256::new_with_prefix("subtxt robust tiles")
+            .chain_update(key.unwrap_or_default())
+            .finalize()
+            .into();
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        let mut cells = (0..CELLS * CELLS).collect::<Vec<_>>();
+        cells.shuffle(&mut rng);
+        let chips = (0..CELLS * CELLS).map(|_| rng.gen()).collect();
+        Pattern { cells, chips }
+    }
+
+    /// Sign of every cell of a tile, row by row, for the payload `bytes`.
+    fn signs(&self, bytes: &[u8]) -> Vec<i32> {
+        let mut signs = vec![0; CELLS * CELLS];
+        for (at, &cell) in self.cells.iter().enumerate() {
+            let bit = match at.checked_sub(LOCATOR) {
+                None => true,
+                Some(at) => bytes
+                    .get(at / SPREAD / 8)
+                    .is_some_and(|byte| byte >> (at / SPREAD % 8) & 1 == 1),
+            };
+            signs[cell] = if bit == self.chips[cell] { 1 } else { -1 };
+        }
+        signs
+    }
+}
+
+/// Writes `bytes` into every tile of `canvas`, returning the number of
+/// bytes written, at most [`CAPACITY`], and of samples changed.
+pub fn write(canvas: &mut Canvas, bytes: &[u8], key: Option<&str>) -> Result<(usize, usize)> {
+    if canvas.is_float() || canvas.palette().is_some() {
+        return Err("the robust mode needs an image with 8 or 16-bit samples".into());
+    }
+    if (canvas.width() as usize) < TILE || (canvas.height() as usize) < TILE {
+        return Err(
+            format!("the robust mode needs an image of at least {TILE}×{TILE} pixels").into(),
+        );
+    }
+
+    let written = bytes.len().min(CAPACITY);
+    let signs = Pattern::new(key).signs(&bytes[..written]);
+    let (width, depth) = (canvas.width() as usize, canvas.depth());
+    let (channels, colour) = (canvas.channels(), canvas.colour_channels());
+    let mut changed = 0;
+    for (pixel, samples) in canvas
+        .samples_mut()
+        .chunks_exact_mut(channels * depth)
+        .enumerate()
+    {
+        let (x, y) = (pixel % width % TILE, pixel / width % TILE);
+        let sign = signs[y / CELL * CELLS + x / CELL];
+        for sample in samples[..colour * depth].chunks_exact_mut(depth) {
+            let old = match depth {
+                1 => sample[0] as i32,
+                _ => u16::from_le_bytes([sample[0], sample[1]]) as i32,
+            };
+            let max = (1 << (8 * depth)) - 1;
+            let new = (old + sign * STRENGTH * (max / 255)).clamp(0, max);
+            match depth {
+                1 => sample[0] = new as u8,
+                _ => sample.copy_from_slice(&(new as u16).to_le_bytes()),
+            }
+            changed += (new != old) as usize;
+        }
+    }
+    Ok((written, changed))
+}
+
+/// Reads the [`CAPACITY`] bytes [`write`] hid in `canvas`, or in a cropped
+/// or rescaled copy of it. Without a payload the bytes are noise.
+pub fn read(canvas: &Canvas, key: Option<&str>) -> Vec<u8> {
+    let plane = Plane::high_pass(canvas);
+    let period = (plane.period(false), plane.period(true));
+    let sums = plane.fold(period);
+    let pattern = Pattern::new(key);
+
+    // The shift of the tile origin at which the locator matches best.
+    let sum = |cells: &[usize], (dx, dy): (usize, usize)| {
+        cells
+            .iter()
+            .map(|&cell| {
+                let (x, y) = (dx + cell % CELLS * CELL, dy + cell / CELLS * CELL);
+                let value = sums[y % TILE * TILE + x % TILE];
+                if pattern.chips[cell] {
+                    value
+                } else {
+                    -value
+                }
+            })
+            .sum::<f32>()
+    };
+    let locator = &pattern.cells[..LOCATOR];
+    let (_, shift) = (0..TILE * TILE)
+        .map(|at| (at % TILE, at / TILE))
+        .map(|shift| (sum(locator, shift), shift))
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap();
+
+    let mut bytes = vec![0; CAPACITY];
+    for (at, cells) in pattern.cells[LOCATOR..].chunks(SPREAD).enumerate() {
+        if sum(cells, shift) > 0.0 {
+            bytes[at / 8] |= 1 << (at % 8);
+        }
+    }
+    bytes
+}
+
+/// Luma of an image with the image itself filtered out.
+struct Plane {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl Plane {
+    /// Luma minus its mean over a box of `2 * RADIUS + 1` pixels a side,
+    /// divided by its local variance, so edges and busy parts of the image
+    /// weigh less than flat ones.
+    fn high_pass(canvas: &Canvas) -> Plane {
+        let (width, height) = (canvas.width() as usize, canvas.height() as usize);
+        let luma = canvas
+            .to_rgba8()
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b, _] = pixel.0.map(|sample| sample as f32);
+                0.299 * r + 0.587 * g + 0.114 * b
+            })
+            .collect::<Vec<_>>();
+
+        let mean = box_mean(&luma, width, height);
+        let detail = luma
+            .iter()
+            .zip(mean)
+            .map(|(value, mean)| value - mean)
+            .collect::<Vec<_>>();
+        let squares = detail.iter().map(|value| value * value).collect::<Vec<_>>();
+        let variance = box_mean(&squares, width, height);
+        let floor = STRENGTH as f32;
+        Plane {
+            width,
+            height,
+            values: detail
+                .iter()
+                .zip(variance)
+                .map(|(value, variance)| value / (variance + floor))
+                .collect(),
+        }
+    }
+
+    /// Value at `along` on line `across`, along the columns when
+    /// `vertical`.
+    fn at(&self, vertical: bool, along: usize, across: usize) -> f32 {
+        match vertical {
+            false => self.values[across * self.width + along],
+            true => self.values[along * self.width + across],
+        }
+    }
+
+    /// Mean product of the values `lag` apart along the lines, within
+    /// `span` of them.
+    fn correlation(&self, vertical: bool, lag: usize, span: (usize, usize)) -> f64 {
+        let (len, lines) = match vertical {
+            false => (self.width, self.height),
+            true => (self.height, self.width),
+        };
+        // A band of at most a tile of lines through the middle.
+        let band = lines.min(TILE);
+        let first = (lines - band) / 2;
+        let (start, end) = (span.0, span.1.min(len));
+        let mut sum = 0.0;
+        for across in first..first + band {
+            for along in start..end.saturating_sub(lag) {
+                sum += (self.at(vertical, along, across) * self.at(vertical, along + lag, across))
+                    as f64;
+            }
+        }
+        sum / (band * end.saturating_sub(lag + start).max(1)) as f64
+    }
+
+    /// Size of the tiles in pixels along the rows, or along the columns
+    /// when `vertical`, [`TILE`] unless a rescaled size stands out.
+    fn period(&self, vertical: bool) -> f64 {
+        let len = if vertical { self.height } else { self.width };
+        // The tile size is searched for in the middle of the image, then
+        // refined over all of it from the multiples of the size.
+        let middle = len.min(4 * TILE);
+        let span = ((len - middle) / 2, (len + middle) / 2);
+        let lags = (TILE as f64 * SCALES.0) as usize
+            ..((TILE as f64 * SCALES.1) as usize).min(middle.saturating_sub(OVERLAP));
+        if lags.len() < 3 {
+            return TILE as f64;
+        }
+        let correlations = lags
+            .clone()
+            .map(|lag| self.correlation(vertical, lag, span))
+            .collect::<Vec<_>>();
+        let mut sorted = correlations.iter().map(|c| c.abs()).collect::<Vec<_>>();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[sorted.len() / 2];
+        let best = correlations.iter().copied().fold(0.0, f64::max);
+        let Some(first) = correlations
+            .iter()
+            .position(|&c| c > best / 2.0)
+            .filter(|_| best > 10.0 * median)
+        else {
+            return TILE as f64;
+        };
+
+        // The first peak, not a multiple of it, then a parabola through it.
+        let mut at = first;
+        while at + 1 < correlations.len() && correlations[at + 1] > correlations[at] {
+            at += 1;
+        }
+        let peak = |lag: usize, [before, here, after]: [f64; 3]| {
+            let curve = before - 2.0 * here + after;
+            match curve < 0.0 {
+                true => lag as f64 + 0.5 * (before - after) / curve,
+                false => lag as f64,
+            }
+        };
+        let neighbours = |at: usize| match (at.checked_sub(1), correlations.get(at + 1)) {
+            (Some(before), Some(&after)) => [correlations[before], correlations[at], after],
+            _ => [0.0; 3],
+        };
+        let mut period = peak(lags.start + at, neighbours(at));
+
+        let whole = (0, len);
+        for multiple in 2.. {
+            let guess = (period * multiple as f64).round() as usize;
+            if guess + 2 + OVERLAP > len {
+                break;
+            }
+            let around = (guess - 2..=guess + 2)
+                .map(|lag| self.correlation(vertical, lag, whole))
+                .collect::<Vec<_>>();
+            let top = (1..4)
+                .max_by(|&a, &b| around[a].total_cmp(&around[b]))
+                .unwrap();
+            let lag = guess - 2 + top;
+            period = peak(lag, [around[top - 1], around[top], around[top + 1]]) / multiple as f64;
+        }
+
+        // Less than half a pixel off over the whole image is no rescaling.
+        match (period - TILE as f64).abs() * (len as f64 / TILE as f64) < 0.5 {
+            true => TILE as f64,
+            false => period,
+        }
+    }
+
+    /// Sums of all tiles of `period` pixels, scaled to [`TILE`], folded
+    /// onto one and summed again over every cell placed at each pixel.
+    fn fold(&self, period: (f64, f64)) -> Vec<f32> {
+        let mut folded = vec![0.0; TILE * TILE];
+        let scale = (TILE as f64 / period.0, TILE as f64 / period.1);
+        let columns = (0..self.width)
+            .map(|x| (x as f64 * scale.0) as usize % TILE)
+            .collect::<Vec<_>>();
+        for (y, line) in self.values.chunks_exact(self.width).enumerate() {
+            let row = (y as f64 * scale.1) as usize % TILE * TILE;
+            for (&column, &value) in columns.iter().zip(line) {
+                folded[row + column] += value;
+            }
+        }
+
+        let mut sums = vec![0.0; TILE * TILE];
+        for y in 0..TILE {
+            for x in 0..TILE {
+                sums[y * TILE + x] = (0..CELL * CELL)
+                    .map(|at| folded[(y + at / CELL) % TILE * TILE + (x + at % CELL) % TILE])
+                    .sum();
+            }
+        }
+        sums
+    }
+}
+
+/// Mean of `values`, `width` a line, over a box of `2 * RADIUS + 1` pixels
+/// a side, or the part of it inside the image.
+fn box_mean(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    // Means along every line through the prefix sums of the line.
+    let means = |values: &[f32], len: usize| {
+        let mut out = Vec::with_capacity(values.len());
+        for line in values.chunks_exact(len) {
+            let mut prefix = vec![0.0f64];
+            prefix.extend(line.iter().scan(0.0, |sum, &value| {
+                *sum += value as f64;
+                Some(*sum)
+            }));
+            out.extend((0..len).map(|at| {
+                let (start, end) = (at.saturating_sub(RADIUS), (at + RADIUS + 1).min(len));
+                ((prefix[end] - prefix[start]) / (end - start) as f64) as f32
+            }));
+        }
+        out
+    };
+    let transpose = |values: &[f32], width: usize, height: usize| {
+        (0..width * height)
+            .map(|at| values[at % height * width + at / height])
+            .collect::<Vec<_>>()
+    };
+    let rows = means(values, width);
+    let columns = means(&transpose(&rows, width, height), height);
+    transpose(&columns, height, width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_repaired_text, embed, extract, extract_payload, Mode, Options};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::imageops::FilterType;
+    use image::{DynamicImage, RgbImage};
+
+    const TEXT: &[u8] = b"meet at the old bridge at noon";
+
+    fn options() -> Options {
+        Options {
+            mode: Mode::Robust,
+            key: Some("key".into()),
+            ecc: ECC,
+            ..Options::default()
+        }
+    }
+
+    /// A photo-like image: smooth shading with grain on top.
+    fn photo(width: u32, height: u32, seed: u64) -> Canvas {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        Canvas::from(RgbImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            let shade = 128.0 + 60.0 * (x / 47.0).sin() * (y / 61.0).cos();
+            image::Rgb(
+                [0.8, 1.0, 1.2].map(|tint| {
+                    (shade * tint + rng.gen_range(-30.0..30.0)).clamp(0.0, 255.0) as u8
+                }),
+            )
+        }))
+    }
+
+    /// `canvas` saved as a JPEG of `quality` and opened again.
+    fn jpeg(canvas: &Canvas, quality: u8) -> Canvas {
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, quality)
+            .encode_image(&canvas.clone().into_image().to_rgb8())
+            .unwrap();
+        Canvas::from(image::load_from_memory(&bytes).unwrap())
+    }
+
+    /// A photo of `width` × `height` pixels carrying [`TEXT`].
+    fn marked(width: u32, height: u32) -> Canvas {
+        let mut canvas = photo(width, height, 1);
+        embed(&mut canvas, TEXT, &options()).unwrap();
+        canvas
+    }
+
+    /// `canvas` changed by `edit` as an editor would.
+    fn edited(canvas: &Canvas, edit: impl FnOnce(DynamicImage) -> DynamicImage) -> Canvas {
+        Canvas::from(edit(canvas.clone().into_image()))
+    }
+
+    #[test]
+    fn text_survives_jpeg_compression() {
+        let options = options();
+        let canvas = marked(600, 450);
+        let copy = jpeg(&canvas, 75);
+
+        // The compression damages a few bytes, which only read back the
+        // same once repaired.
+        let (payload, repaired) = extract_payload(&copy, &options).unwrap();
+        assert!(repaired > Some(0));
+        assert_eq!(payload.data, TEXT);
+        assert_eq!(
+            decode_repaired_text(&copy, &options).unwrap(),
+            decode_repaired_text(&canvas, &options).unwrap()
+        );
+    }
+
+    #[test]
+    fn text_survives_a_crop_off_the_tile_grid() {
+        // About one and a half tiles cut off the left and the top.
+        let copy = edited(&marked(900, 800), |image| {
+            image.crop_imm(389, 371, 511, 429)
+        });
+        assert_eq!(extract(&copy, &options()).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn text_survives_shrinking_to_two_thirds() {
+        let copy = edited(&marked(900, 600), |image| {
+            image.resize_exact(600, 400, FilterType::Lanczos3)
+        });
+        assert_eq!(extract(&copy, &options()).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn text_survives_enlarging_by_half() {
+        let copy = edited(&marked(600, 450), |image| {
+            image.resize_exact(900, 675, FilterType::CatmullRom)
+        });
+        assert_eq!(extract(&copy, &options()).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn text_survives_a_crop_rescaled() {
+        let copy = edited(&marked(900, 700), |image| {
+            image
+                .crop_imm(133, 77, 640, 560)
+                .resize_exact(512, 448, FilterType::Triangle)
+        });
+        assert_eq!(extract(&copy, &options()).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn image_smaller_than_a_tile_is_refused() {
+        let mut canvas = photo(300, 200, 2);
+        let error = embed(&mut canvas, TEXT, &options()).unwrap_err();
+        assert!(error.to_string().contains("at least 256×256"), "{error}");
+
+        // A crop that small no longer holds the text.
+        let copy = edited(&marked(600, 450), |image| image.crop_imm(50, 50, 200, 200));
+        assert!(extract(&copy, &options()).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use subtxt::image::{Rgba, RgbaImage};
+
+/// An empty directory for `name`, removed first if an earlier run left it.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("subtxt-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary in `dir` with `args`, without any `SUBTXT_` variables
+/// of the environment running the tests.
+fn subtxt(dir: &Path, args: &[&str]) -> Output {
+    subtxt_with(dir, args, &[])
+}
+
+/// [`subtxt`] with the variables `vars` set.
+fn subtxt_with(dir: &Path, args: &[&str], vars: &[(&str, &str)]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_subtxt"));
+    for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with("SUBTXT_")) {
+        command.env_remove(key);
+    }
+    command.envs(vars.iter().copied());
+    command.current_dir(dir).args(args).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// An image of `size` pixels a side, transparent but for a border.
+fn image(dir: &Path, name: &str, size: u32) {
+    RgbaImage::from_fn(size, size, |x, y| match x.min(y) < 4 {
+        true => Rgba([200, 100, 50, 255]),
+        false => Rgba([0; 4]),
+    })
+    .save(dir.join(name))
+    .unwrap();
+}
+
+/// Generates an identity in `file`, returning its public key.
+fn keygen(dir: &Path, file: &str) -> String {
+    let output = subtxt(dir, &["keygen", "-o", file]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = fs::read_to_string(dir.join(file)).unwrap();
+    let public = text.lines().next().unwrap();
+    public.strip_prefix("# public key: ").unwrap().to_string()
+}
+
+#[test]
+fn adding_keeps_every_recipient() {
+    let dir = scratch("recipients");
+    image(&dir, "image.png", 128);
+    let (a, b) = (keygen(&dir, "a.txt"), keygen(&dir, "b.txt"));
+    fs::write(dir.join("note.txt"), "first").unwrap();
+    fs::write(dir.join("more.txt"), "second").unwrap();
+
+    let output = subtxt(&dir, &["add", "image.png", "note.txt", "-r", &a, "-r", &b]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // The recipients cannot be read from the archive, so they are needed.
+    let output = subtxt(&dir, &["add", "image.png", "more.txt", "-k", "a.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("pass -r"), "{}", stderr(&output));
+
+    let args = [
+        "add",
+        "image.png",
+        "more.txt",
+        "-k",
+        "a.txt",
+        "-r",
+        &a,
+        "-r",
+        &b,
+    ];
+    let output = subtxt(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = subtxt(&dir, &["list", "image.png", "-k", "b.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("note.txt") && listing.contains("more.txt"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn passphrase_variable_does_not_encrypt_alone() {
+    let dir = scratch("passphrase");
+    image(&dir, "image.png", 128);
+    fs::write(dir.join("note.txt"), "hidden").unwrap();
+    let vars = [("SUBTXT_PASSPHRASE", "secret")];
+    let embed = |output, extra: &[&str]| {
+        let mut args = vec!["image.png", "-i", "note.txt", "-o", output];
+        args.extend(extra);
+        let output = subtxt_with(&dir, &args, &vars);
+        assert!(output.status.success(), "{}", stderr(&output));
+    };
+    let probe = |image| String::from_utf8(subtxt(&dir, &["probe", image]).stdout).unwrap();
+
+    embed("plain.png", &[]);
+    assert!(probe("plain.png").contains("encryption: none"));
+    let output = subtxt(&dir, &["plain.png", "-p"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("hidden"));
+
+    embed("sealed.png", &["-e"]);
+    assert!(probe("sealed.png").contains("encryption: passphrase"));
+    let output = subtxt_with(&dir, &["sealed.png", "-p"], &vars);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("hidden"));
+
+    // The variable is not a passphrase given on the command line.
+    let key = keygen(&dir, "key.txt");
+    embed("recipient.png", &["-r", &key]);
+    assert!(probe("recipient.png").contains("encryption: recipients"));
+    fs::remove_dir_all(dir).unwrap();
+}